@@ -18,6 +18,7 @@ pub mod plugin;
 pub mod protocol;
 #[cfg(feature = "public")]
 pub mod replicate;
+pub mod rng;
 pub mod stage;
 pub mod tick;
 
@@ -33,15 +34,25 @@ pub struct Local;
 pub mod prelude {
     #[cfg(feature = "public")]
     pub use crate::protocol::{
-        ClientChannel, Owned, ServerChannel, ServerEntities, ServerEntity, ServerMessage,
+        ClientChannel, ClientCommand, ClientCommandAppExt, CorrectionVisual, Extrapolated,
+        InterpolateAppExt, Interpolated, LagCompensated, LagCompensation, MessageTarget,
+        NetworkEvent, NetworkEventAppExt, Owned, Ownership, OwnershipAssigned,
+        OwnershipPredicted, OwnershipRevoked, PlayerConnected, PlayerDisconnected, Predicted,
+        PredictionKey, RelayInputs, RollbackResourceAppExt, SendCommand, SendNetworkEvent,
+        SendServerMessage, ServerChannel, ServerEntities, ServerEntity, ServerMessage,
+        ServerMessageAppExt, ServerMessageEvent, SmoothCorrection, SubTick, SubTickInputs,
     };
 
     pub use crate::error::SabiError;
-    pub use crate::lobby::{ClientId, Lobby};
+    pub use crate::lobby::{ClientId, InputRouting, LocalIndex, LocalPlayer, Lobby};
+    pub use crate::rng::NetworkRng;
+    pub use crate::stage::{
+        is_first_simulation, is_resimulating, FirstSimulationEvents, IsResimulating,
+    };
     pub use crate::tick::{tick_hz, NetworkTick};
 
     #[cfg(feature = "public")]
-    pub use crate::plugin::{ReplicatePlugin, SabiPlugin};
+    pub use crate::plugin::{ClientAccumulateInput, ReplicatePlugin, SabiPlugin};
     #[cfg(feature = "public")]
     pub use crate::replicate::{Replicate, ReplicateId};
 }
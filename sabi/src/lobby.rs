@@ -1,10 +1,192 @@
 use bevy::prelude::*;
-use bevy::utils::HashMap;
+use bevy::utils::{HashMap, HashSet};
 
 pub type ClientId = u64;
 
+/// Index of a player sharing a client's connection for split-screen, `0` is the main player.
+pub type LocalIndex = u8;
+
 /// Renet Client ID -> Player Character Entity mapping
+///
+/// What a client controls is up to `Ownership`, which can give them more entities than
+/// just their character, e.g. units in an RTS or a vehicle. Inputs get routed to those
+/// based on their `InputRouting`.
+///
+/// Split-screen players past the main player live in `local_players`, they only
+/// ever receive their own inputs.
 #[derive(Debug, Default, Resource)]
 pub struct Lobby {
     pub players: HashMap<ClientId, Entity>,
+    pub local_players: HashMap<(ClientId, LocalIndex), Entity>,
+    pub routing: HashMap<ClientId, InputRouting>,
+}
+
+/// Which of a client's owned entities should receive its inputs.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum InputRouting {
+    /// Only the player's character.
+    Player,
+    /// Every entity the client owns.
+    #[default]
+    All,
+    /// Only these entities, as long as the client owns them.
+    Selected(HashSet<Entity>),
+}
+
+impl Lobby {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every client that has a player.
+    pub fn clients(&self) -> HashSet<ClientId> {
+        self.players
+            .keys()
+            .chain(self.local_players.keys().map(|(client_id, _)| client_id))
+            .cloned()
+            .collect()
+    }
+
+    pub fn player(&self, client_id: ClientId, index: LocalIndex) -> Option<Entity> {
+        match index {
+            0 => self.players.get(&client_id).cloned(),
+            _ => self.local_players.get(&(client_id, index)).cloned(),
+        }
+    }
+
+    pub fn insert_player(&mut self, client_id: ClientId, index: LocalIndex, entity: Entity) {
+        match index {
+            0 => self.players.insert(client_id, entity),
+            _ => self.local_players.insert((client_id, index), entity),
+        };
+    }
+
+    pub fn remove_player(&mut self, client_id: ClientId, index: LocalIndex) -> Option<Entity> {
+        match index {
+            0 => self.players.remove(&client_id),
+            _ => self.local_players.remove(&(client_id, index)),
+        }
+    }
+
+    /// Every player including split-screen players.
+    pub fn iter_players(&self) -> impl Iterator<Item = ((ClientId, LocalIndex), Entity)> + '_ {
+        self.players
+            .iter()
+            .map(|(client_id, entity)| ((*client_id, 0), *entity))
+            .chain(
+                self.local_players
+                    .iter()
+                    .map(|(key, entity)| (*key, *entity)),
+            )
+    }
+
+    pub fn set_routing(&mut self, client_id: ClientId, routing: InputRouting) {
+        self.routing.insert(client_id, routing);
+    }
+
+    /// Route a client's inputs to only these entities.
+    pub fn select(&mut self, client_id: ClientId, entities: impl IntoIterator<Item = Entity>) {
+        self.set_routing(
+            client_id,
+            InputRouting::Selected(entities.into_iter().collect()),
+        );
+    }
+
+    /// Entities that should receive a client's input.
+    ///
+    /// `owned` are the entities the client owns in `Ownership`, the player isn't
+    /// included unless they own it.
+    ///
+    /// Split-screen players are left out since they get their own inputs.
+    pub fn input_targets(
+        &self,
+        client_id: ClientId,
+        owned: impl IntoIterator<Item = Entity>,
+    ) -> HashSet<Entity> {
+        let mut controlled = owned.into_iter().collect::<HashSet<_>>();
+
+        for ((local_client_id, _), entity) in self.local_players.iter() {
+            if *local_client_id == client_id {
+                controlled.remove(entity);
+            }
+        }
+
+        match self.routing.get(&client_id) {
+            Some(InputRouting::Player) => self
+                .players
+                .get(&client_id)
+                .filter(|player| controlled.contains(player))
+                .cloned()
+                .into_iter()
+                .collect(),
+            Some(InputRouting::All) | None => controlled,
+            Some(InputRouting::Selected(selected)) => {
+                controlled.intersection(selected).cloned().collect()
+            }
+        }
+    }
+
+    /// Forget everything about a client.
+    pub fn remove(&mut self, client_id: &ClientId) {
+        self.players.remove(client_id);
+        self.local_players
+            .retain(|(local_client_id, _), _| local_client_id != client_id);
+        self.routing.remove(client_id);
+    }
+}
+
+/// Our own Renet Client ID, as told to us by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Resource)]
+pub struct LocalPlayer(pub ClientId);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn input_targets() {
+        let player = Entity::from_raw(0);
+        let unit_a = Entity::from_raw(1);
+        let unit_b = Entity::from_raw(2);
+        let vehicle = Entity::from_raw(3);
+
+        let mut lobby = Lobby::new();
+        lobby.players.insert(1, player);
+
+        let targets = lobby.input_targets(1, [player, unit_a, unit_b, vehicle]);
+        assert_eq!(targets.len(), 4);
+
+        lobby.set_routing(1, InputRouting::Player);
+        let targets = lobby.input_targets(1, [player, vehicle]);
+        assert_eq!(targets.into_iter().collect::<Vec<_>>(), vec![player]);
+
+        // Having a player doesn't mean we own it.
+        assert!(lobby.input_targets(1, [vehicle]).is_empty());
+
+        // Selecting something we don't control shouldn't route to it.
+        lobby.select(1, [unit_b, Entity::from_raw(4)]);
+        let targets = lobby.input_targets(1, [unit_a, unit_b]);
+        assert_eq!(targets.into_iter().collect::<Vec<_>>(), vec![unit_b]);
+
+        // Selected but no longer owned.
+        assert!(lobby.input_targets(1, [unit_a]).is_empty());
+    }
+
+    #[test]
+    pub fn local_players() {
+        let player = Entity::from_raw(0);
+        let second_player = Entity::from_raw(1);
+
+        let mut lobby = Lobby::new();
+        lobby.insert_player(1, 0, player);
+        lobby.insert_player(1, 1, second_player);
+        assert_eq!(lobby.player(1, 1), Some(second_player));
+
+        // The second player owning their character shouldn't route the main player's input to it.
+        let targets = lobby.input_targets(1, [player, second_player]);
+        assert_eq!(targets.into_iter().collect::<Vec<_>>(), vec![player]);
+
+        lobby.remove(&1);
+        assert_eq!(lobby.iter_players().count(), 0);
+    }
 }
@@ -10,8 +10,8 @@ use iyes_loopless::prelude::{ConditionHelpers, IntoConditionalSystem};
 use serde::{Deserialize, Serialize};
 
 use crate::stage::{
-    NetworkCoreStage, NetworkSimulationAppExt, NetworkSimulationInfo, NetworkSimulationStage,
-    NetworkStage,
+    IsResimulating, NetworkCoreStage, NetworkSimulationAppExt, NetworkSimulationInfo,
+    NetworkSimulationStage, NetworkStage,
 };
 #[cfg(feature = "public")]
 use crate::{
@@ -46,6 +46,26 @@ where
 #[derive(SystemLabel, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ServerQueueInterest;
 
+/// Use `tolerance` for `C` unless the game already inserted its own.
+#[cfg(feature = "public")]
+pub(crate) fn init_prediction_tolerance<C>(
+    app: &mut App,
+    tolerance: crate::protocol::resim::PredictionTolerance<C>,
+) where
+    C: 'static + Send + Sync,
+{
+    if !app
+        .world
+        .contains_resource::<crate::protocol::resim::PredictionTolerance<C>>()
+    {
+        app.insert_resource(tolerance);
+    }
+}
+
+/// Where the client samples each frame's input when using an `InputMergePolicy`.
+#[derive(SystemLabel, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ClientAccumulateInput;
+
 #[cfg(feature = "public")]
 impl<C> Plugin for ReplicatePlugin<C>
 where
@@ -78,11 +98,40 @@ where
                     .run_if_resource_exists::<NetworkTick>()
                     .run_if(client_connected),
             );
-            app.add_rewind_network_system(crate::protocol::resim::rewind::<C>);
+            // Snapshots are stored again while replaying, so they match the corrected timeline.
+            app.add_input_history_network_system(
+                crate::protocol::resim::store_snapshot::<C>
+                    .run_if_resource_exists::<RenetClient>()
+                    .run_if_resource_exists::<NetworkTick>()
+                    .run_if(client_connected),
+            );
+            app.add_rewind_network_system(
+                crate::protocol::resim::rewind::<C>
+                    .label("rewind_components")
+                    .after("rewind_predicted_entities"),
+            );
+            app.add_meta_network_system(
+                crate::protocol::spawn::client_move_duplicate_component::<C>
+                    .after("client_recv_messages")
+                    .before("client_despawn_duplicate_spawns"),
+            );
+
+            app.init_resource::<crate::protocol::resim::PredictionTolerance<C>>();
+            app.add_meta_network_system(
+                crate::protocol::resim::client_check_prediction::<C>
+                    .run_if_resource_exists::<NetworkTick>()
+                    .label("client_check_prediction")
+                    .after("client_recv_interest"),
+            );
         }
     }
 }
 
+/// Sets up networking for the input `I`, as a server or client depending on whether
+/// `sabi::Server` or `sabi::Client` was inserted.
+///
+/// Settings like `MissingInputPolicy` or `LagCompensationSettings` are added with
+/// `init_resource`, so insert your own before adding this plugin to change the defaults.
 #[derive(Debug, Clone)]
 pub struct SabiPlugin<I> {
     pub phantom: PhantomData<I>,
@@ -168,6 +217,12 @@ where
         if !app.world.contains_resource::<NetworkSimulationInfo>() {
             app.insert_resource(NetworkSimulationInfo::new(self.tick_rate));
         }
+        app.init_resource::<IsResimulating>();
+        // Clients get the server's seed when they connect.
+        if !app.world.contains_resource::<crate::rng::NetworkRng>() {
+            app.insert_resource(crate::rng::NetworkRng::new(rand::random()));
+        }
+        app.add_system_to_network_stage(NetworkCoreStage::First, crate::rng::reseed_network_rng);
 
         app.insert_resource(Lobby::default());
 
@@ -188,6 +243,23 @@ where
 
         //app.add_apply_update_network_system(bevy::transform::transform_propagate_system);
 
+        // Floating point error shouldn't cause a rewind.
+        #[cfg(feature = "public")]
+        init_prediction_tolerance(
+            app,
+            crate::protocol::resim::PredictionTolerance::<Transform>::Within(
+                |predicted, server| {
+                    predicted.translation.distance(server.translation) < 0.01
+                        && predicted.rotation.angle_between(server.rotation) < 0.01
+                },
+            ),
+        );
+        // Propagated from `Transform` after the tick, so it never matches exactly.
+        #[cfg(feature = "public")]
+        init_prediction_tolerance(
+            app,
+            crate::protocol::resim::PredictionTolerance::<GlobalTransform>::Ignore,
+        );
         #[cfg(feature = "public")]
         app.add_plugin(ReplicatePlugin::<Transform>::default());
         #[cfg(feature = "public")]
@@ -225,14 +297,29 @@ where
         //app.insert_resource(crate::protocol::interest::SentInterests::new());
 
         app.insert_resource(crate::protocol::update::ClientEntityUpdates::new());
+        app.insert_resource(crate::protocol::event::ClientNetworkEvents::new());
+        app.insert_resource(crate::protocol::input::ClientRelayedInputs::new());
 
         app.insert_resource(crate::protocol::ack::ClientAcks::new());
+        app.insert_resource(crate::protocol::ownership::Ownership::new());
 
         app.insert_resource(crate::protocol::demands::ReplicateSizeEstimates::new());
         app.insert_resource(crate::protocol::demands::ReplicateMaxSize::default());
         app.insert_resource(crate::protocol::input::ClientQueuedInputs::<I>::new());
+        app.insert_resource(crate::protocol::input::ClientMissingInputs::new());
+        app.init_resource::<crate::protocol::input::MissingInputPolicy<I>>();
+        app.init_resource::<crate::protocol::validation::InputValidation<I>>();
+        app.insert_resource(crate::protocol::validation::ClientInputViolations::new());
+        app.add_event::<crate::protocol::validation::InputViolationEvent>();
         app.insert_resource(crate::protocol::input::ClientReceivedHistory::new());
 
+        app.init_resource::<crate::protocol::lag_compensation::LagCompensationSettings>();
+        app.insert_resource(crate::protocol::lag_compensation::ColliderHistory::new());
+        app.insert_resource(crate::protocol::lag_compensation::ClientViewTicks::new());
+        app.add_meta_network_system(
+            crate::protocol::lag_compensation::server_record_collider_history,
+        );
+
         app.add_plugin(bevy_renet::RenetServerPlugin {
             clear_events: false,
         });
@@ -261,6 +348,25 @@ where
             crate::protocol::interest::queue_interests.label("queue_interests"),
         );
 
+        app.add_meta_network_system(
+            crate::protocol::input::server_relay_inputs::<I>
+                .run_if_resource_exists::<crate::protocol::input::RelayInputs>()
+                .after("apply_input")
+                .before("server_send_interest"),
+        );
+
+        app.add_meta_network_system(
+            crate::protocol::message::server_send_messages
+                .run_if_resource_exists::<RenetServer>()
+                .label("server_send_messages"),
+        );
+
+        app.add_meta_network_system(
+            crate::protocol::spawn::server_confirm_predicted_spawns
+                .run_if_resource_exists::<RenetServer>()
+                .before("server_send_interest"),
+        );
+
         app.add_meta_network_system(
             server_send_interest
                 .run_if_resource_exists::<RenetServer>()
@@ -270,6 +376,14 @@ where
         app.add_meta_network_system(
             crate::protocol::update::server_clear_queue.after("server_send_interest"),
         );
+
+        // After anything that could still queue something up for them this tick.
+        app.add_meta_network_system(
+            crate::protocol::server::server_clean_disconnected
+                .after("apply_input")
+                .after("server_send_messages")
+                .after("server_send_interest"),
+        );
     }
 }
 
@@ -302,6 +416,28 @@ where
         app.add_network_system_set(RenetClientPlugin::get_clear_event_systems());
 
         app.insert_resource(crate::protocol::update::UpdateMessages::new());
+        app.insert_resource(crate::protocol::resim::Mispredictions::new());
+        app.insert_resource(crate::protocol::resim::PredictedEntities::new());
+        app.add_rollback_resource::<crate::rng::NetworkRng>();
+        app.insert_resource(crate::protocol::event::ReceivedNetworkEvents::new());
+        app.insert_resource(crate::protocol::input::LocalQueuedInputs::<I>::new());
+        app.init_resource::<crate::protocol::input::SubTick>();
+        app.insert_resource(crate::protocol::input::AccumulatedInputs::<I>::new());
+        app.init_resource::<crate::protocol::input::InputLead>();
+
+        app.add_event::<PlayerConnected>();
+        app.add_event::<PlayerDisconnected>();
+        app.add_event::<OwnershipAssigned>();
+        app.add_event::<OwnershipRevoked>();
+        app.init_resource::<crate::protocol::message::ReceivedServerMessages>();
+
+        app.add_meta_network_system(
+            crate::protocol::message::client_recv_messages
+                .run_if_resource_exists::<RenetClient>()
+                .run_if(client_connected)
+                .label("client_recv_messages")
+                .before("client_recv_interest"),
+        );
 
         app.add_meta_network_system(
             crate::protocol::update::client_recv_interest
@@ -309,6 +445,11 @@ where
                 .run_if(client_connected)
                 .label("client_recv_interest"),
         );
+        app.add_meta_network_system(
+            crate::protocol::update::client_clear_checked_updates
+                .run_if_resource_exists::<NetworkTick>()
+                .after("client_check_prediction"),
+        );
         app.add_update_history_network_system(
             crate::protocol::update::client_apply_server_update
                 .run_if_resource_exists::<RenetClient>()
@@ -316,6 +457,84 @@ where
                 .label("client_apply_server_update"),
         );
 
+        app.add_system_to_network_stage(
+            NetworkCoreStage::First,
+            crate::protocol::input::client_apply_remote_inputs::<I>
+                .run_if_resource_exists::<NetworkTick>(),
+        );
+
+        app.add_meta_network_system(
+            crate::protocol::resim::store_predicted_entities
+                .run_if_resource_exists::<NetworkTick>(),
+        );
+        app.add_input_history_network_system(
+            crate::protocol::resim::store_predicted_entities
+                .run_if_resource_exists::<NetworkTick>(),
+        );
+        app.init_resource::<crate::protocol::spawn::PredictedSpawnSettings>();
+        app.insert_resource(crate::protocol::spawn::AdoptedSpawns::new());
+        // Runs every tick, replays included, after the game has spawned things.
+        app.add_system_to_network_stage(
+            NetworkCoreStage::Last,
+            crate::protocol::spawn::client_dedup_predicted_spawns,
+        );
+        app.add_meta_network_system(
+            crate::protocol::spawn::client_despawn_duplicate_spawns
+                .label("client_despawn_duplicate_spawns")
+                .after("client_recv_messages"),
+        );
+        app.add_meta_network_system(
+            crate::protocol::spawn::client_timeout_predicted_spawns
+                .run_if_resource_exists::<NetworkTick>(),
+        );
+        app.add_rewind_network_system(
+            crate::protocol::resim::rewind_predicted_entities
+                .run_if_resource_exists::<NetworkTick>()
+                .label("rewind_predicted_entities"),
+        );
+
+        app.init_resource::<crate::protocol::extrapolation::DeadReckoningSettings>();
+        app.add_system_to_stage(
+            CoreStage::PreUpdate,
+            crate::protocol::extrapolation::client_setup_dead_reckoning
+                .run_if_resource_exists::<NetworkTick>(),
+        );
+        app.add_meta_network_system(
+            crate::protocol::extrapolation::client_dead_reckoning_update
+                .after("client_recv_interest"),
+        );
+        app.add_system_to_network_stage(
+            NetworkCoreStage::First,
+            crate::protocol::extrapolation::client_extrapolate,
+        );
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            crate::protocol::extrapolation::client_dead_reckoning
+                .run_if_resource_exists::<NetworkTick>()
+                .before(bevy::transform::TransformSystem::TransformPropagate),
+        );
+
+        app.init_resource::<crate::protocol::smoothing::CorrectionSettings>();
+        // Has to see where things were before anything is rewound.
+        app.add_rewind_network_system(
+            crate::protocol::smoothing::client_record_pre_rewind
+                .before("rewind_predicted_entities")
+                .before("rewind_components"),
+        );
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            crate::protocol::smoothing::client_smooth_corrections
+                .before(bevy::transform::TransformSystem::TransformPropagate),
+        );
+
+        // Sample after the game has updated its input for the frame, but before the
+        // network stage so this frame's input makes it into this frame's tick.
+        app.add_system_to_stage(
+            CoreStage::PreUpdate,
+            crate::protocol::input::client_accumulate_input::<I>
+                .run_if_resource_exists::<crate::protocol::input::InputMergePolicy<I>>()
+                .label(ClientAccumulateInput),
+        );
         app.add_meta_network_system(
             crate::protocol::input::client_update_input_buffer::<I>
                 .run_if_resource_exists::<NetworkTick>()
@@ -378,6 +597,10 @@ pub fn handle_client_disconnect(
             error!("client disconnected: {}", reason);
             commands.remove_resource::<RenetClient>();
             commands.remove_resource::<NetworkTick>();
+            commands.remove_resource::<LocalPlayer>();
+            // Event ids start over with a new connection.
+            commands.insert_resource(crate::protocol::event::ReceivedNetworkEvents::new());
+            commands.insert_resource(crate::protocol::resim::PredictedEntities::new());
         }
     } else {
         if server.is_none() && tick.is_some() {
@@ -76,6 +76,10 @@ impl ServerEntities {
         }
     }
 
+    pub fn insert(&mut self, server_entity: ServerEntity, entity: Entity) {
+        self.0.insert(server_entity, entity);
+    }
+
     pub fn get(&self, entities: &Entities, server_entity: ServerEntity) -> Option<Entity> {
         let entity = self.0.get(&server_entity).cloned();
         entity.filter(|entity| entities.contains(*entity))
@@ -0,0 +1,297 @@
+use std::marker::PhantomData;
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::{RenetClient, RenetServer};
+use iyes_loopless::prelude::{ConditionHelpers, IntoConditionalSystem};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    prelude::*,
+    protocol::{
+        client_connected,
+        pending::{PendingEvents, PendingEventsAppExt},
+    },
+    stage::NetworkSimulationAppExt,
+};
+
+/// Identifier for a message type so both sides know what they are deserializing.
+///
+/// FNV-1a of the name it was registered with, so it is the same for any build as long
+/// as the name is. `type_name` and `DefaultHasher` can both change between compilers.
+pub const fn message_id(name: &str) -> u64 {
+    let bytes = name.as_bytes();
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        i += 1;
+    }
+    hash
+}
+
+/// Id `T` was registered with as a command, server message or network event.
+#[derive(Debug, Resource)]
+pub struct MessageId<T> {
+    pub name: &'static str,
+    pub id: u64,
+    marker: PhantomData<T>,
+}
+
+impl<T> MessageId<T> {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            id: message_id(name),
+            marker: PhantomData,
+        }
+    }
+}
+
+/// Insert the id for `T`, it has to be registered under the same name everywhere.
+pub fn register_message_id<T>(app: &mut App, name: &'static str) -> u64
+where
+    T: 'static + Send + Sync,
+{
+    if let Some(registered) = app.world.get_resource::<MessageId<T>>() {
+        if registered.name != name {
+            panic!(
+                "{} is registered as both {:?} and {:?}",
+                std::any::type_name::<T>(),
+                registered.name,
+                name
+            );
+        }
+    }
+
+    let id = MessageId::<T>::new(name);
+    let raw_id = id.id;
+    app.insert_resource(id);
+    raw_id
+}
+
+/// Discrete action from a client, sent reliably alongside its continuous input.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandMessage {
+    pub id: u64,
+    pub tick: NetworkTick,
+    pub data: Vec<u8>,
+}
+
+impl CommandMessage {
+    pub fn protocol_id() -> u64 {
+        1
+    }
+}
+
+/// Send a command to the server, it will be stamped with the current tick.
+#[derive(Debug, Clone)]
+pub struct SendCommand<T>(pub T);
+
+/// Command received by the server from a client.
+#[derive(Debug, Clone)]
+pub struct ClientCommand<T> {
+    pub client_id: ClientId,
+    /// Tick the client issued this command on.
+    pub tick: NetworkTick,
+    pub command: T,
+}
+
+/// Commands received this tick that haven't been turned into events yet.
+#[derive(Default, Debug, Clone, Resource)]
+pub struct ReceivedCommands {
+    commands: HashMap<u64, Vec<(ClientId, NetworkTick, Vec<u8>)>>,
+}
+
+impl ReceivedCommands {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, id: u64) {
+        self.commands.entry(id).or_default();
+    }
+
+    pub fn push(&mut self, client_id: ClientId, message: CommandMessage) -> bool {
+        match self.commands.get_mut(&message.id) {
+            Some(commands) => {
+                commands.push((client_id, message.tick, message.data));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn drain(&mut self, id: u64) -> Vec<(ClientId, NetworkTick, Vec<u8>)> {
+        self.commands
+            .get_mut(&id)
+            .map(|commands| commands.drain(..).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Registers `T` as a command clients can send to the server.
+///
+/// `name` identifies it over the network, so it has to match on the client and server.
+pub struct ClientCommandPlugin<T> {
+    pub name: &'static str,
+    marker: PhantomData<T>,
+}
+
+impl<T> ClientCommandPlugin<T> {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Plugin for ClientCommandPlugin<T>
+where
+    T: 'static + Send + Sync + Clone + Serialize + DeserializeOwned,
+{
+    fn build(&self, app: &mut App) {
+        let id = register_message_id::<T>(app, self.name);
+
+        if app.world.contains_resource::<crate::Server>() {
+            app.add_event::<ClientCommand<T>>();
+
+            if !app.world.contains_resource::<ReceivedCommands>() {
+                app.insert_resource(ReceivedCommands::new());
+                app.add_meta_network_system(
+                    server_recv_commands
+                        .run_if_resource_exists::<RenetServer>()
+                        .label("server_recv_commands"),
+                );
+            }
+
+            app.world.resource_mut::<ReceivedCommands>().register(id);
+
+            app.add_meta_network_system(
+                server_dispatch_commands::<T>.after("server_recv_commands"),
+            );
+        }
+
+        if app.world.contains_resource::<crate::Client>() {
+            app.add_pending_events::<SendCommand<T>>();
+            app.add_meta_network_system(
+                client_send_commands::<T>
+                    .run_if_resource_exists::<RenetClient>()
+                    .run_if_resource_exists::<NetworkTick>()
+                    .run_if(client_connected)
+                    .after("hold_pending_events"),
+            );
+        }
+    }
+}
+
+pub fn client_send_commands<T>(
+    id: Res<MessageId<T>>,
+    mut pending: ResMut<PendingEvents<SendCommand<T>>>,
+    mut client: ResMut<RenetClient>,
+) where
+    T: 'static + Send + Sync + Serialize,
+{
+    for (tick, SendCommand(command)) in pending.drain() {
+        let message = CommandMessage {
+            id: id.id,
+            tick,
+            data: bincode::serialize(&command).unwrap(),
+        };
+
+        client.send_message(
+            ClientChannel::Command.id(),
+            bincode::serialize(&message).unwrap(),
+        );
+    }
+}
+
+pub fn server_recv_commands(
+    mut received: ResMut<ReceivedCommands>,
+    mut server: ResMut<RenetServer>,
+) {
+    for client_id in server.clients_id().into_iter() {
+        while let Some(message) = server.receive_message(client_id, ClientChannel::Command.id()) {
+            match bincode::deserialize::<CommandMessage>(&message) {
+                Ok(message) => {
+                    let id = message.id;
+                    if !received.push(client_id, message) {
+                        warn!("client {} sent unregistered command {}", client_id, id);
+                    }
+                }
+                Err(err) => {
+                    error!("could not deserialize command from {}: {}", client_id, err);
+                }
+            }
+        }
+    }
+}
+
+pub fn server_dispatch_commands<T>(
+    id: Res<MessageId<T>>,
+    mut received: ResMut<ReceivedCommands>,
+    mut events: EventWriter<ClientCommand<T>>,
+) where
+    T: 'static + Send + Sync + DeserializeOwned,
+{
+    for (client_id, tick, data) in received.drain(id.id) {
+        match bincode::deserialize::<T>(&data) {
+            Ok(command) => events.send(ClientCommand {
+                client_id,
+                tick,
+                command,
+            }),
+            Err(err) => error!(
+                "could not deserialize {} from {}: {}",
+                std::any::type_name::<T>(),
+                client_id,
+                err
+            ),
+        }
+    }
+}
+
+pub trait ClientCommandAppExt {
+    /// Register `T` as a command clients can send to the server under `name`.
+    fn add_client_command<T>(&mut self, name: &'static str) -> &mut Self
+    where
+        T: 'static + Send + Sync + Clone + Serialize + DeserializeOwned;
+}
+
+impl ClientCommandAppExt for App {
+    fn add_client_command<T>(&mut self, name: &'static str) -> &mut Self
+    where
+        T: 'static + Send + Sync + Clone + Serialize + DeserializeOwned,
+    {
+        self.add_plugin(ClientCommandPlugin::<T>::new(name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::pending::hold_between_ticks;
+
+    #[test]
+    pub fn message_ids() {
+        // Has to stay the same across builds, so pin it to known FNV-1a values.
+        assert_eq!(message_id(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(message_id("a"), 0xaf63_dc4c_8601_ec8c);
+        assert_ne!(message_id("buy_item"), message_id("drop_weapon"));
+    }
+
+    #[test]
+    pub fn commands_between_ticks() {
+        let commands = hold_between_ticks((0..4).map(SendCommand).collect())
+            .into_iter()
+            .map(|(tick, SendCommand(command))| (tick, command))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            (0..4)
+                .map(|command| (NetworkTick::new(10), command))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,407 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    marker::PhantomData,
+};
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::RenetServer;
+use iyes_loopless::prelude::IntoConditionalSystem;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    prelude::*,
+    protocol::{
+        command::{register_message_id, MessageId},
+        pending::{PendingEvents, PendingEventsAppExt},
+    },
+    stage::NetworkSimulationAppExt,
+};
+
+/// How many update messages an event is repeated in before we give up on it.
+pub const EVENT_REDUNDANCY: u8 = 8;
+/// How many event ids back the client remembers for deduplicating.
+pub const EVENT_DEDUP_BUFFER: u64 = 1024;
+/// How many ticks an event waits to be sent before we give up on it.
+pub const EVENT_TIMEOUT_TICKS: u64 = 32;
+
+/// Fire-and-forget event piggybacked on an `UpdateMessage`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkEventMessage {
+    /// Per-client sequential id, starts at 1.
+    pub id: u64,
+    pub kind: u64,
+    pub tick: NetworkTick,
+    pub data: Vec<u8>,
+}
+
+/// Send an unreliable event to clients.
+#[derive(Debug, Clone)]
+pub struct SendNetworkEvent<T> {
+    pub target: MessageTarget,
+    pub tick: NetworkTick,
+    pub event: T,
+}
+
+/// Unreliable event received from the server, fires once per event.
+#[derive(Debug, Clone)]
+pub struct NetworkEvent<T> {
+    pub tick: NetworkTick,
+    pub event: T,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutgoingEvent {
+    pub message: NetworkEventMessage,
+    /// Tick we queued it on.
+    pub queued: NetworkTick,
+    pub sends: u8,
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct OutgoingEvents {
+    next_id: u64,
+    unacked: VecDeque<OutgoingEvent>,
+}
+
+impl OutgoingEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, queued: NetworkTick, kind: u64, tick: NetworkTick, data: Vec<u8>) {
+        self.next_id += 1;
+        self.unacked.push_back(OutgoingEvent {
+            message: NetworkEventMessage {
+                id: self.next_id,
+                kind,
+                tick,
+                data,
+            },
+            queued,
+            sends: 0,
+        });
+    }
+
+    /// Client has received every event up to and including `id`.
+    ///
+    /// Events are always resent together, so anything before the latest one the
+    /// client has seen was either in the same packet or given up on.
+    pub fn ack(&mut self, id: u64) {
+        self.unacked.retain(|event| event.message.id > id);
+    }
+
+    /// Give up on events that have been waiting too long, even if they were never sent.
+    pub fn clean_old(&mut self, current: NetworkTick) {
+        self.unacked
+            .retain(|event| event.queued.tick() + EVENT_TIMEOUT_TICKS > current.tick());
+    }
+
+    /// Events to include in the next update, dropping any we have sent enough times.
+    pub fn to_send(&mut self) -> Vec<NetworkEventMessage> {
+        let mut events = Vec::new();
+        for event in self.unacked.iter_mut() {
+            events.push(event.message.clone());
+            event.sends += 1;
+        }
+
+        self.unacked.retain(|event| event.sends < EVENT_REDUNDANCY);
+        events
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unacked.is_empty()
+    }
+}
+
+/// Server-side queue of unreliable events for each client.
+#[derive(Default, Debug, Clone, Resource)]
+pub struct ClientNetworkEvents {
+    clients: BTreeMap<ClientId, OutgoingEvents>,
+}
+
+impl ClientNetworkEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(
+        &mut self,
+        client_id: ClientId,
+        queued: NetworkTick,
+        kind: u64,
+        tick: NetworkTick,
+        data: Vec<u8>,
+    ) {
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .push(queued, kind, tick, data);
+    }
+
+    pub fn ack(&mut self, client_id: ClientId, id: u64) {
+        if let Some(events) = self.clients.get_mut(&client_id) {
+            events.ack(id);
+        }
+    }
+
+    pub fn to_send(&mut self, client_id: &ClientId) -> Vec<NetworkEventMessage> {
+        self.clients
+            .get_mut(client_id)
+            .map(|events| events.to_send())
+            .unwrap_or_default()
+    }
+
+    pub fn has_pending(&self, client_id: &ClientId) -> bool {
+        self.clients
+            .get(client_id)
+            .map(|events| !events.is_empty())
+            .unwrap_or(false)
+    }
+
+    /// Give up on old events, including those for clients we never sent an update to.
+    pub fn clean_old(&mut self, current: NetworkTick) {
+        for events in self.clients.values_mut() {
+            events.clean_old(current);
+        }
+
+        self.clients.retain(|_, events| !events.is_empty());
+    }
+
+    pub fn remove(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+/// Client-side deduplication of unreliable events.
+#[derive(Default, Debug, Clone, Resource)]
+pub struct ReceivedNetworkEvents {
+    /// Highest event id we have seen, sent back to the server as an ack.
+    pub latest: u64,
+    seen: BTreeSet<u64>,
+    pending: HashMap<u64, Vec<(NetworkTick, Vec<u8>)>>,
+}
+
+impl ReceivedNetworkEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns false if we've already seen this event.
+    pub fn receive(&mut self, event: NetworkEventMessage) -> bool {
+        if event.id + EVENT_DEDUP_BUFFER <= self.latest || !self.seen.insert(event.id) {
+            return false;
+        }
+
+        self.latest = self.latest.max(event.id);
+        let oldest = self.latest.saturating_sub(EVENT_DEDUP_BUFFER);
+        self.seen = self.seen.split_off(&oldest);
+
+        self.pending
+            .entry(event.kind)
+            .or_default()
+            .push((event.tick, event.data));
+        true
+    }
+
+    pub fn drain(&mut self, kind: u64) -> Vec<(NetworkTick, Vec<u8>)> {
+        self.pending
+            .get_mut(&kind)
+            .map(|events| events.drain(..).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Registers `T` as an unreliable event the server can send to clients.
+///
+/// `name` identifies it over the network, so it has to match on the client and server.
+pub struct NetworkEventPlugin<T> {
+    pub name: &'static str,
+    marker: PhantomData<T>,
+}
+
+impl<T> NetworkEventPlugin<T> {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Plugin for NetworkEventPlugin<T>
+where
+    T: 'static + Send + Sync + Clone + Serialize + DeserializeOwned,
+{
+    fn build(&self, app: &mut App) {
+        register_message_id::<T>(app, self.name);
+
+        if app.world.contains_resource::<crate::Server>() {
+            app.add_pending_events::<SendNetworkEvent<T>>();
+            app.add_meta_network_system(
+                server_queue_network_events::<T>
+                    .run_if_resource_exists::<RenetServer>()
+                    .after("hold_pending_events")
+                    .before("server_send_interest"),
+            );
+        }
+
+        if app.world.contains_resource::<crate::Client>() {
+            app.add_event::<NetworkEvent<T>>();
+            // Meta systems are never replayed, so these only fire once even when resimulating.
+            app.add_meta_network_system(
+                client_dispatch_network_events::<T>.after("client_recv_interest"),
+            );
+        }
+    }
+}
+
+pub trait NetworkEventAppExt {
+    /// Register `T` as an unreliable event the server can send to clients under `name`.
+    fn add_network_event<T>(&mut self, name: &'static str) -> &mut Self
+    where
+        T: 'static + Send + Sync + Clone + Serialize + DeserializeOwned;
+}
+
+impl NetworkEventAppExt for App {
+    fn add_network_event<T>(&mut self, name: &'static str) -> &mut Self
+    where
+        T: 'static + Send + Sync + Clone + Serialize + DeserializeOwned,
+    {
+        self.add_plugin(NetworkEventPlugin::<T>::new(name))
+    }
+}
+
+pub fn server_queue_network_events<T>(
+    id: Res<MessageId<T>>,
+    tick: Res<NetworkTick>,
+    mut pending: ResMut<PendingEvents<SendNetworkEvent<T>>>,
+    mut outgoing: ResMut<ClientNetworkEvents>,
+    server: Res<RenetServer>,
+) where
+    T: 'static + Send + Sync + Serialize,
+{
+    let kind = id.id;
+    for (_, event) in pending.drain() {
+        let data = bincode::serialize(&event.event).unwrap();
+
+        let clients = match &event.target {
+            MessageTarget::Client(client_id) => vec![*client_id],
+            MessageTarget::Clients(client_ids) => client_ids.clone(),
+            MessageTarget::All => server.clients_id(),
+            MessageTarget::AllExcept(except) => server
+                .clients_id()
+                .into_iter()
+                .filter(|client_id| client_id != except)
+                .collect(),
+        };
+
+        for client_id in clients {
+            outgoing.push(client_id, *tick, kind, event.tick, data.clone());
+        }
+    }
+}
+
+pub fn client_dispatch_network_events<T>(
+    id: Res<MessageId<T>>,
+    mut received: ResMut<ReceivedNetworkEvents>,
+    mut events: EventWriter<NetworkEvent<T>>,
+) where
+    T: 'static + Send + Sync + DeserializeOwned,
+{
+    for (tick, data) in received.drain(id.id) {
+        match bincode::deserialize::<T>(&data) {
+            Ok(event) => events.send(NetworkEvent { tick, event }),
+            Err(err) => error!(
+                "could not deserialize {}: {}",
+                std::any::type_name::<T>(),
+                err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::pending::hold_between_ticks;
+
+    fn event(id: u64) -> NetworkEventMessage {
+        NetworkEventMessage {
+            id,
+            kind: 0,
+            tick: NetworkTick::new(id),
+            data: Vec::new(),
+        }
+    }
+
+    #[test]
+    pub fn redundancy() {
+        let queued = NetworkTick::new(1);
+        let mut outgoing = OutgoingEvents::new();
+        outgoing.push(queued, 0, NetworkTick::new(1), Vec::new());
+        outgoing.push(queued, 0, NetworkTick::new(2), Vec::new());
+
+        for _ in 0..EVENT_REDUNDANCY {
+            assert_eq!(outgoing.to_send().len(), 2);
+        }
+        assert!(outgoing.is_empty());
+
+        outgoing.push(queued, 0, NetworkTick::new(3), Vec::new());
+        outgoing.push(queued, 0, NetworkTick::new(4), Vec::new());
+        outgoing.ack(3);
+        let ids = outgoing
+            .to_send()
+            .into_iter()
+            .map(|event| event.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![4]);
+    }
+
+    #[test]
+    pub fn expire_unsent() {
+        let mut outgoing = ClientNetworkEvents::new();
+        outgoing.push(1, NetworkTick::new(10), 0, NetworkTick::new(10), Vec::new());
+        outgoing.push(2, NetworkTick::new(20), 0, NetworkTick::new(20), Vec::new());
+
+        // Never sent, but still too old to be worth sending.
+        outgoing.clean_old(NetworkTick::new(10 + EVENT_TIMEOUT_TICKS));
+        assert!(!outgoing.has_pending(&1));
+        assert!(outgoing.has_pending(&2));
+
+        outgoing.remove(&2);
+        assert!(!outgoing.has_pending(&2));
+    }
+
+    #[test]
+    pub fn events_between_ticks() {
+        let events = hold_between_ticks(
+            (0..4)
+                .map(|event| SendNetworkEvent {
+                    target: MessageTarget::All,
+                    tick: NetworkTick::new(10),
+                    event,
+                })
+                .collect(),
+        )
+        .into_iter()
+        .map(|(_, event)| event.event)
+        .collect::<Vec<_>>();
+        assert_eq!(events, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    pub fn dedup() {
+        let mut received = ReceivedNetworkEvents::new();
+        assert!(received.receive(event(1)));
+        assert!(received.receive(event(3)));
+        assert!(!received.receive(event(1)));
+        assert!(received.receive(event(2)));
+        assert!(!received.receive(event(3)));
+        assert_eq!(received.latest, 3);
+        assert_eq!(received.drain(0).len(), 3);
+
+        assert!(received.receive(event(2 + EVENT_DEDUP_BUFFER * 2)));
+        // Way too old to tell if we've seen it, so ignore it.
+        assert!(!received.receive(event(4)));
+    }
+}
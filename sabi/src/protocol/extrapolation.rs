@@ -0,0 +1,326 @@
+use bevy::{ecs::entity::Entities, prelude::*};
+use bevy_rapier3d::prelude::Velocity;
+
+use crate::{prelude::*, stage::NetworkSimulationInfo};
+
+use super::update::UpdateMessages;
+
+/// Project this entity forward from its last server update using its `Velocity`
+/// instead of predicting it, so it keeps moving when updates stop arriving.
+///
+/// Extrapolated entities aren't `Predicted`, so they are never rewound.
+///
+/// `Transform` is kept at the projected pose for the current tick, the smoothed pose
+/// between ticks is applied to any children with `CorrectionVisual`.
+#[derive(Default, Debug, Clone, Copy, Component)]
+pub struct Extrapolated;
+
+/// Limits for extrapolating entities.
+#[derive(Debug, Clone, Resource)]
+pub struct DeadReckoningSettings {
+    /// Most ticks we will project past the last server update.
+    pub max_ticks: u64,
+    /// Corrections further off than this snap instead of blending.
+    pub max_error: f32,
+    /// Roughly how many seconds it takes to blend out a correction.
+    pub blend_time: f32,
+}
+
+impl Default for DeadReckoningSettings {
+    fn default() -> Self {
+        Self {
+            max_ticks: 16,
+            max_error: 2.0,
+            blend_time: 0.1,
+        }
+    }
+}
+
+/// Last authoritative state of an extrapolated entity.
+#[derive(Default, Debug, Clone, Component)]
+pub struct DeadReckoning {
+    pub tick: NetworkTick,
+    pub transform: Transform,
+    pub velocity: Velocity,
+    /// Visual error left over from the last correction, blended out over time.
+    pub translation_error: Vec3,
+    pub rotation_error: Quat,
+    /// Last transform we rendered and the fractional tick it was for.
+    rendered: Option<(f64, Transform)>,
+}
+
+impl DeadReckoning {
+    pub fn new(tick: NetworkTick, transform: Transform, velocity: Velocity) -> Self {
+        Self {
+            tick,
+            transform,
+            velocity,
+            ..Default::default()
+        }
+    }
+
+    /// Where the entity should be at a fractional tick.
+    pub fn project(&self, tick: f64, step: f32, max_ticks: u64) -> Transform {
+        let ticks = (tick - self.tick.tick() as f64).clamp(0.0, max_ticks as f64);
+        let delta = ticks as f32 * step;
+
+        Transform {
+            translation: self.transform.translation + self.velocity.linvel * delta,
+            rotation: Quat::from_scaled_axis(self.velocity.angvel * delta)
+                * self.transform.rotation,
+            scale: self.transform.scale,
+        }
+    }
+
+    /// New authoritative state from the server, keeping how far off we were
+    /// so it can be blended out instead of snapping.
+    pub fn correct(
+        &mut self,
+        tick: NetworkTick,
+        transform: Option<Transform>,
+        velocity: Option<Velocity>,
+        step: f32,
+        settings: &DeadReckoningSettings,
+    ) {
+        if tick < self.tick {
+            return;
+        }
+
+        // Only changed components get sent, so fill in whatever is missing.
+        let transform =
+            transform.unwrap_or_else(|| self.project(tick.tick() as f64, step, settings.max_ticks));
+        self.velocity = velocity.unwrap_or(self.velocity);
+        self.transform = transform;
+        self.tick = tick;
+
+        if let Some((rendered_tick, rendered)) = self.rendered {
+            let projected = self.project(rendered_tick, step, settings.max_ticks);
+            let error = rendered.translation - projected.translation;
+
+            if error.length() > settings.max_error {
+                self.translation_error = Vec3::ZERO;
+                self.rotation_error = Quat::IDENTITY;
+            } else {
+                self.translation_error = error;
+                self.rotation_error = rendered.rotation * projected.rotation.inverse();
+            }
+        }
+    }
+
+    /// Transform to render at a fractional tick, `delta` seconds after the last render.
+    pub fn render(
+        &mut self,
+        tick: f64,
+        step: f32,
+        delta: f32,
+        settings: &DeadReckoningSettings,
+    ) -> Transform {
+        let blend = if settings.blend_time > 0.0 {
+            (-delta / settings.blend_time).exp()
+        } else {
+            0.0
+        };
+        self.translation_error *= blend;
+        self.rotation_error = Quat::IDENTITY.slerp(self.rotation_error, blend);
+
+        let mut transform = self.project(tick, step, settings.max_ticks);
+        transform.translation += self.translation_error;
+        transform.rotation = self.rotation_error * transform.rotation;
+
+        self.rendered = Some((tick, transform));
+        transform
+    }
+}
+
+pub fn client_setup_dead_reckoning(
+    mut commands: Commands,
+    tick: Res<NetworkTick>,
+    query: Query<
+        (Entity, &Transform, Option<&Velocity>),
+        (With<Extrapolated>, Without<DeadReckoning>),
+    >,
+) {
+    for (entity, transform, velocity) in query.iter() {
+        let velocity = velocity.cloned().unwrap_or_default();
+        commands
+            .entity(entity)
+            .insert(DeadReckoning::new(*tick, *transform, velocity));
+    }
+}
+
+/// Pick up any server updates we just got for extrapolated entities.
+pub fn client_dead_reckoning_update(
+    entities: &Entities,
+    sim_info: Res<NetworkSimulationInfo>,
+    settings: Res<DeadReckoningSettings>,
+    server_updates: Res<UpdateMessages>,
+    server_entities: Res<ServerEntities>,
+    mut query: Query<&mut DeadReckoning, With<Extrapolated>>,
+) {
+    let step = sim_info.step.as_secs_f32();
+
+    for tick in server_updates.received() {
+        let update = match server_updates.get(tick) {
+            Some(update) => update,
+            None => continue,
+        };
+
+        for (server_entity, components) in update.entity_update.iter() {
+            let entity = match server_entities.get(entities, *server_entity) {
+                Some(entity) => entity,
+                None => continue,
+            };
+
+            let mut dead_reckoning = match query.get_mut(entity) {
+                Ok(dead_reckoning) => dead_reckoning,
+                Err(_) => continue,
+            };
+
+            let transform = components
+                .get(&Transform::replicate_id())
+                .and_then(|data| bincode::deserialize::<<Transform as Replicate>::Def>(data).ok())
+                .map(Transform::from_def);
+            let velocity = components
+                .get(&Velocity::replicate_id())
+                .and_then(|data| bincode::deserialize::<<Velocity as Replicate>::Def>(data).ok())
+                .map(Velocity::from_def);
+
+            if transform.is_none() && velocity.is_none() {
+                continue;
+            }
+
+            dead_reckoning.correct(*tick, transform, velocity, step, &*settings);
+        }
+    }
+}
+
+/// Keep the simulated `Transform` at where we project the entity to be this tick.
+pub fn client_extrapolate(
+    tick: Res<NetworkTick>,
+    sim_info: Res<NetworkSimulationInfo>,
+    settings: Res<DeadReckoningSettings>,
+    mut query: Query<(&DeadReckoning, &mut Transform), With<Extrapolated>>,
+) {
+    for (dead_reckoning, mut transform) in query.iter_mut() {
+        *transform = dead_reckoning.project(
+            tick.tick() as f64,
+            sim_info.step.as_secs_f32(),
+            settings.max_ticks,
+        );
+    }
+}
+
+/// Show the smoothed pose between ticks on `CorrectionVisual` children.
+pub fn client_dead_reckoning(
+    time: Res<Time>,
+    tick: Res<NetworkTick>,
+    sim_info: Res<NetworkSimulationInfo>,
+    settings: Res<DeadReckoningSettings>,
+    mut query: Query<(&mut DeadReckoning, &Transform, Option<&Children>), With<Extrapolated>>,
+    mut visuals: Query<&mut Transform, (With<CorrectionVisual>, Without<Extrapolated>)>,
+) {
+    let render_tick = tick.tick() as f64 + sim_info.overstep();
+    for (mut dead_reckoning, transform, children) in query.iter_mut() {
+        let rendered = dead_reckoning.render(
+            render_tick,
+            sim_info.step.as_secs_f32(),
+            time.delta_seconds(),
+            &*settings,
+        );
+
+        let children = match children {
+            Some(children) => children,
+            None => continue,
+        };
+
+        // Rendered pose is in world space, visuals are relative to us.
+        let inverse_rotation = transform.rotation.inverse();
+        for child in children.iter() {
+            if let Ok(mut visual) = visuals.get_mut(*child) {
+                visual.translation =
+                    inverse_rotation * (rendered.translation - transform.translation);
+                visual.rotation = inverse_rotation * rendered.rotation;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn dead_reckoning() {
+        let settings = DeadReckoningSettings::default();
+        let step = 0.1;
+
+        let mut dead_reckoning = DeadReckoning::new(
+            NetworkTick::new(10),
+            Transform::default(),
+            Velocity::linear(Vec3::X),
+        );
+        let rendered = dead_reckoning.render(20.0, step, 0.0, &settings);
+        assert!((rendered.translation.x - 1.0).abs() < 1e-4);
+
+        // Don't run off forever if updates stop.
+        let projected = dead_reckoning.project(100.0, step, settings.max_ticks);
+        assert!((projected.translation.x - 1.6).abs() < 1e-4);
+
+        // Server says we were behind, blend towards it instead of popping.
+        dead_reckoning.correct(
+            NetworkTick::new(20),
+            Some(Transform::from_xyz(0.5, 0.0, 0.0)),
+            None,
+            step,
+            &settings,
+        );
+        let rendered = dead_reckoning.render(20.0, step, 0.0, &settings);
+        assert!((rendered.translation.x - 1.0).abs() < 1e-4);
+        let rendered = dead_reckoning.render(20.0, step, 10.0, &settings);
+        assert!((rendered.translation.x - 0.5).abs() < 1e-4);
+
+        // Way off, so just snap.
+        dead_reckoning.correct(
+            NetworkTick::new(20),
+            Some(Transform::from_xyz(10.0, 0.0, 0.0)),
+            None,
+            step,
+            &settings,
+        );
+        let rendered = dead_reckoning.render(20.0, step, 0.0, &settings);
+        assert!((rendered.translation.x - 10.0).abs() < 1e-4);
+    }
+
+    #[test]
+    pub fn render_through_visual() {
+        let mut world = World::new();
+        let step = tick_hz(10);
+        world.insert_resource(Time::default());
+        world.insert_resource(NetworkTick::new(12));
+        world.insert_resource(NetworkSimulationInfo::new(step));
+        world.insert_resource(DeadReckoningSettings::default());
+
+        let mut dead_reckoning = DeadReckoning::new(
+            NetworkTick::new(10),
+            Transform::default(),
+            Velocity::linear(Vec3::X),
+        );
+        dead_reckoning.translation_error = Vec3::Y;
+        let visual = world.spawn((Transform::default(), CorrectionVisual)).id();
+        let entity = world
+            .spawn((Transform::default(), dead_reckoning, Extrapolated))
+            .push_children(&[visual])
+            .id();
+
+        let mut schedule = SystemStage::single(client_extrapolate);
+        let mut post_update = SystemStage::single(client_dead_reckoning);
+        schedule.run(&mut world);
+        post_update.run(&mut world);
+
+        // The simulated pose doesn't pick up the visual error.
+        let transform = world.get::<Transform>(entity).unwrap();
+        assert!((transform.translation - Vec3::new(0.2, 0.0, 0.0)).length() < 1e-4);
+        let visual = world.get::<Transform>(visual).unwrap();
+        assert!((visual.translation - Vec3::Y).length() < 1e-4);
+    }
+}
@@ -1,6 +1,9 @@
 use std::collections::VecDeque;
 use std::fmt::Debug;
-use std::{collections::BTreeMap, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
 
 use bevy::{
     prelude::*,
@@ -12,10 +15,15 @@ use bevy_renet::renet::{RenetClient, RenetServer};
 
 use serde::{Deserialize, Serialize};
 
-use crate::prelude::*;
+use crate::{prelude::*, stage::NetworkSimulationInfo};
 
 use super::{
     ack::{ClientAcks, NetworkAck},
+    event::{ClientNetworkEvents, ReceivedNetworkEvents},
+    interpolation::InterpolationClock,
+    lag_compensation::ClientViewTicks,
+    update::UpdateMessages,
+    validation::{ClientInputViolations, InputValidation, InputViolation, InputViolationEvent},
     ClientId, NetworkTick,
 };
 
@@ -23,12 +31,109 @@ use super::{
 pub const INPUT_RETAIN_BUFFER: i64 = 32;
 /// How many inputs we should send to the server for future ticks.
 pub const INPUT_SEND_BUFFER: i64 = 12;
+/// How many input messages we look at when reporting the input margin.
+pub const INPUT_MARGIN_WINDOW: usize = 16;
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct InputDeviation {
     pub deviation: f32,
 }
 
+/// How many ticks early (positive) or late (negative) a client's inputs are
+/// arriving at the server.
+///
+/// This is the worst case over the last `INPUT_MARGIN_WINDOW` input messages.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputMargin {
+    pub ticks: i32,
+}
+
+/// How far ahead of the server the client should try to keep its inputs.
+///
+/// The client speeds up or slows down its timestep to keep the `InputMargin`
+/// the server reports within `tolerance` of `target`.
+#[derive(Debug, Clone, Resource)]
+pub struct InputLead {
+    /// Ticks early we want our inputs to arrive.
+    pub target: i32,
+    /// Ticks off the target we are fine with before adjusting.
+    pub tolerance: i32,
+    /// How much to adjust the timestep by for each tick we are off the target.
+    pub adjust_per_tick: f64,
+    /// Most we will ever adjust the timestep by.
+    pub max_adjust: f64,
+}
+
+impl Default for InputLead {
+    fn default() -> Self {
+        Self {
+            target: 2,
+            tolerance: 1,
+            adjust_per_tick: 0.01,
+            max_adjust: 0.05,
+        }
+    }
+}
+
+impl InputLead {
+    /// Steer the simulation towards our target margin.
+    pub fn steer(&self, sim_info: &mut NetworkSimulationInfo, margin: InputMargin) {
+        let error = margin.ticks - self.target;
+        let adjust = (self.adjust_per_tick * error.abs() as f64).min(self.max_adjust);
+
+        if error > self.tolerance {
+            // Inputs are arriving earlier than they need to, slow down to cut latency.
+            sim_info.decel(adjust);
+        } else if error < -self.tolerance {
+            // Inputs are arriving too late, speed up so the server has them in time.
+            sim_info.accel(adjust);
+        } else {
+            sim_info.accel(0.0);
+        }
+    }
+}
+
+/// How far into a tick an input was sampled, in 255ths of a tick.
+///
+/// Available as a resource on the client and as a component next to the input on
+/// the server, so things like hit registration don't have to round to the tick.
+#[derive(
+    Default,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    Component,
+    Resource,
+)]
+pub struct SubTick(pub u8);
+
+impl SubTick {
+    pub fn from_overstep(overstep: f64) -> Self {
+        Self((overstep.clamp(0.0, 1.0) * u8::MAX as f64).round() as u8)
+    }
+
+    /// Fraction of a tick from `0.0` to `1.0`.
+    pub fn fraction(&self) -> f32 {
+        self.0 as f32 / u8::MAX as f32
+    }
+
+    /// Time into the tick for a given timestep.
+    pub fn offset(&self, step: Duration) -> Duration {
+        step.mul_f32(self.fraction())
+    }
+}
+
+/// Insert this on the client to send `SubTick`s along with our inputs.
+#[derive(Default, Debug, Clone, Copy, Resource)]
+pub struct SubTickInputs;
+
 #[derive(Default, Debug, Clone, Resource)]
 pub struct ClientReceivedHistory {
     clients: BTreeMap<ClientId, ReceivedHistory>,
@@ -46,12 +151,26 @@ impl ClientReceivedHistory {
     pub fn deviation(&mut self, client_id: ClientId) -> InputDeviation {
         self.clients.entry(client_id).or_default().deviation()
     }
+
+    pub fn push_margin(&mut self, client_id: ClientId, margin: i32) {
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .push_margin(margin);
+    }
+
+    pub fn margin(&self, client_id: &ClientId) -> Option<InputMargin> {
+        self.clients
+            .get(client_id)
+            .and_then(|history| history.margin())
+    }
 }
 
 #[derive(Default, Debug, Clone)]
 pub struct ReceivedHistory {
     previous: Option<Duration>,
     times: VecDeque<f32>,
+    margins: VecDeque<i32>,
 }
 
 impl ReceivedHistory {
@@ -72,6 +191,21 @@ impl ReceivedHistory {
         self.previous = Some(sample);
     }
 
+    pub fn push_margin(&mut self, margin: i32) {
+        self.margins.push_back(margin);
+
+        if self.margins.len() > INPUT_MARGIN_WINDOW {
+            self.margins.pop_front();
+        }
+    }
+
+    pub fn margin(&self) -> Option<InputMargin> {
+        self.margins
+            .iter()
+            .min()
+            .map(|ticks| InputMargin { ticks: *ticks })
+    }
+
     pub fn deviation(&self) -> InputDeviation {
         if self.times.len() == 0 {
             return InputDeviation::default();
@@ -99,22 +233,281 @@ impl ReceivedHistory {
     }
 }
 
+/// What the server should do when a client's input for the current tick hasn't
+/// arrived, `Keep` by default.
+#[derive(Resource)]
+pub enum MissingInputPolicy<I> {
+    /// Leave whatever input the entity already had.
+    Keep,
+    /// Repeat the last input we received.
+    Repeat,
+    /// Blend the last input we received toward `I::default()`, reaching it
+    /// once the input is `ticks` old.
+    ///
+    /// `blend` gets the last input, the default and how far along we are from `0.0` to `1.0`.
+    Decay {
+        ticks: u64,
+        blend: fn(&I, &I, f32) -> I,
+    },
+    /// Guess the input from the last input we received and how many ticks old it is.
+    Extrapolate(fn(&I, u64) -> I),
+}
+
+impl<I> Default for MissingInputPolicy<I> {
+    fn default() -> Self {
+        Self::Keep
+    }
+}
+
+impl<I> MissingInputPolicy<I>
+where
+    I: Clone + Default,
+{
+    /// Predict the input for `tick` given the last input we received.
+    pub fn predict(&self, tick: NetworkTick, last: Option<(&NetworkTick, &I)>) -> Option<I> {
+        let (last_tick, last_input) = last?;
+        let missed = tick.tick().saturating_sub(last_tick.tick());
+
+        match self {
+            Self::Keep => None,
+            Self::Repeat => Some(last_input.clone()),
+            Self::Decay { ticks, .. } if missed >= *ticks => Some(I::default()),
+            Self::Decay { ticks, blend } => Some(blend(
+                last_input,
+                &I::default(),
+                missed as f32 / *ticks as f32,
+            )),
+            Self::Extrapolate(extrapolate) => Some(extrapolate(last_input, missed)),
+        }
+    }
+}
+
+/// How the client combines every frame's input since the last tick into the input for a tick.
+///
+/// Without this only the input on the frame of the tick is used, and anything in
+/// between is lost.
+///
+/// Frames are sampled at the end of `CoreStage::PreUpdate`, so update your input
+/// before `ClientAccumulateInput` if you do it in that stage.
+#[derive(Resource)]
+pub enum InputMergePolicy<I> {
+    /// Only use the input from the frame of the tick, same as not having a policy.
+    Latest,
+    /// Merge each frame's input into what we have so far, e.g. OR-ing button
+    /// presses together and taking the latest axes.
+    Merge(fn(&mut I, &I)),
+}
+
+impl<I> Default for InputMergePolicy<I> {
+    fn default() -> Self {
+        Self::Latest
+    }
+}
+
+impl<I> InputMergePolicy<I>
+where
+    I: Clone,
+{
+    pub fn merge(&self, accumulated: &mut Option<I>, sample: &I) {
+        if let (Self::Merge(merge), Some(accumulated)) = (self, accumulated.as_mut()) {
+            merge(accumulated, sample);
+            return;
+        }
+
+        *accumulated = Some(sample.clone());
+    }
+}
+
+/// Inputs sampled every frame since the last tick.
+///
+/// `sub_tick` is when the input last changed, so a press from an earlier frame
+/// keeps the sub-tick it happened on.
+#[derive(Debug, Clone, Resource)]
+pub struct AccumulatedInputs<I> {
+    pub input: Option<I>,
+    pub local_inputs: BTreeMap<LocalIndex, I>,
+    pub sub_tick: Option<SubTick>,
+    pub local_sub_ticks: BTreeMap<LocalIndex, SubTick>,
+    /// Last frame's samples, serialized, to tell when they change.
+    previous: Option<Vec<u8>>,
+    local_previous: BTreeMap<LocalIndex, Vec<u8>>,
+}
+
+impl<I> Default for AccumulatedInputs<I> {
+    fn default() -> Self {
+        Self {
+            input: None,
+            local_inputs: Default::default(),
+            sub_tick: None,
+            local_sub_ticks: Default::default(),
+            previous: None,
+            local_previous: Default::default(),
+        }
+    }
+}
+
+impl<I> AccumulatedInputs<I> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// How often we have had to predict a client's input.
+#[derive(Default, Debug, Clone)]
+pub struct MissingInputHistory {
+    /// Ticks we didn't have an input for.
+    pub missing: u64,
+    /// Ticks we didn't have an input for that did show up afterwards.
+    pub late: u64,
+    pending: BTreeSet<NetworkTick>,
+}
+
+impl MissingInputHistory {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn missed(&mut self, tick: NetworkTick) {
+        if self.pending.insert(tick) {
+            self.missing += 1;
+        }
+    }
+
+    pub fn arrived(&mut self, tick: &NetworkTick) {
+        if self.pending.remove(tick) {
+            self.late += 1;
+        }
+    }
+
+    /// Stop waiting on inputs that are too old to ever show up.
+    pub fn clean_old(&mut self, current: NetworkTick) {
+        self.pending
+            .retain(|tick| (current.tick() as i64) - (tick.tick() as i64) < INPUT_RETAIN_BUFFER);
+    }
+}
+
+/// Missing inputs for each player, split-screen players are counted separately.
+#[derive(Default, Debug, Clone, Resource)]
+pub struct ClientMissingInputs {
+    clients: BTreeMap<(ClientId, LocalIndex), MissingInputHistory>,
+}
+
+impl ClientMissingInputs {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn get(&self, client_id: ClientId, index: LocalIndex) -> Option<&MissingInputHistory> {
+        self.clients.get(&(client_id, index))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&(ClientId, LocalIndex), &MissingInputHistory)> {
+        self.clients.iter()
+    }
+
+    pub fn missed(&mut self, client_id: ClientId, index: LocalIndex, tick: NetworkTick) {
+        self.clients
+            .entry((client_id, index))
+            .or_default()
+            .missed(tick);
+    }
+
+    pub fn arrived(&mut self, client_id: ClientId, index: LocalIndex, tick: &NetworkTick) {
+        if let Some(history) = self.clients.get_mut(&(client_id, index)) {
+            history.arrived(tick);
+        }
+    }
+
+    pub fn clean_old(&mut self, current: NetworkTick) {
+        for (_, history) in &mut self.clients {
+            history.clean_old(current);
+        }
+    }
+
+    /// Forget a client along with their split-screen players.
+    pub fn remove(&mut self, client_id: &ClientId) {
+        self.clients
+            .retain(|(missing_client_id, _), _| missing_client_id != client_id);
+    }
+}
+
+/// Insert this on the server to forward the inputs of other players near a client's
+/// own players, so the client can predict them like its own character.
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct RelayInputs {
+    /// Players further than this from all of a client's players aren't relayed to it.
+    pub radius: f32,
+}
+
+impl Default for RelayInputs {
+    fn default() -> Self {
+        Self { radius: 50.0 }
+    }
+}
+
+impl RelayInputs {
+    pub fn in_range(&self, a: Vec3, b: Vec3) -> bool {
+        a.distance_squared(b) <= self.radius * self.radius
+    }
+}
+
+/// Other players' inputs to send to each client this tick.
+#[derive(Default, Debug, Clone, Resource)]
+pub struct ClientRelayedInputs {
+    clients: BTreeMap<ClientId, BTreeMap<ServerEntity, Vec<u8>>>,
+}
+
+impl ClientRelayedInputs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, client_id: ClientId, server_entity: ServerEntity, input: Vec<u8>) {
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .insert(server_entity, input);
+    }
+
+    pub fn take(&mut self, client_id: &ClientId) -> BTreeMap<ServerEntity, Vec<u8>> {
+        self.clients.remove(client_id).unwrap_or_default()
+    }
+
+    pub fn has_pending(&self, client_id: &ClientId) -> bool {
+        self.clients
+            .get(client_id)
+            .map_or(false, |inputs| !inputs.is_empty())
+    }
+
+    pub fn remove(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientInputMessage<I> {
     pub tick: NetworkTick,
     pub ack: NetworkAck,
+    /// Latest unreliable event we've received from the server.
+    pub event_ack: u64,
+    /// Server tick we were seeing remote entities at, for lag compensation.
+    pub view_tick: Option<NetworkTick>,
     pub inputs: QueuedInputs<I>,
+    /// Inputs for split-screen players past the main player.
+    pub local_inputs: BTreeMap<LocalIndex, QueuedInputs<I>>,
 }
 
 #[derive(Debug, Clone, Resource)]
 pub struct ClientQueuedInputs<I> {
     clients: HashMap<ClientId, QueuedInputs<I>>,
+    local_clients: HashMap<(ClientId, LocalIndex), QueuedInputs<I>>,
 }
 
 impl<I> ClientQueuedInputs<I> {
     pub fn new() -> Self {
         Self {
             clients: HashMap::new(),
+            local_clients: HashMap::new(),
         }
     }
 
@@ -122,6 +515,58 @@ impl<I> ClientQueuedInputs<I> {
         self.clients.get(&client).and_then(|queue| queue.get(tick))
     }
 
+    pub fn get_local(&self, client: ClientId, index: LocalIndex, tick: &NetworkTick) -> Option<&I> {
+        self.local_clients
+            .get(&(client, index))
+            .and_then(|queue| queue.get(tick))
+    }
+
+    pub fn sub_tick(&self, client: ClientId, tick: &NetworkTick) -> Option<SubTick> {
+        self.clients
+            .get(&client)
+            .and_then(|queue| queue.sub_tick(tick))
+    }
+
+    pub fn sub_tick_local(
+        &self,
+        client: ClientId,
+        index: LocalIndex,
+        tick: &NetworkTick,
+    ) -> Option<SubTick> {
+        self.local_clients
+            .get(&(client, index))
+            .and_then(|queue| queue.sub_tick(tick))
+    }
+
+    /// Most recent input before `tick`.
+    pub fn last(&self, client: ClientId, tick: &NetworkTick) -> Option<(&NetworkTick, &I)> {
+        self.clients
+            .get(&client)
+            .and_then(|queue| queue.last_before(tick))
+    }
+
+    pub fn last_local(
+        &self,
+        client: ClientId,
+        index: LocalIndex,
+        tick: &NetworkTick,
+    ) -> Option<(&NetworkTick, &I)> {
+        self.local_clients
+            .get(&(client, index))
+            .and_then(|queue| queue.last_before(tick))
+    }
+
+    pub fn upsert_local(&mut self, client: ClientId, index: LocalIndex, input: QueuedInputs<I>) {
+        match self.local_clients.entry((client, index)) {
+            Entry::Occupied(mut entry) => {
+                entry.get_mut().apply_buffer(input);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(input);
+            }
+        }
+    }
+
     pub fn upsert(&mut self, client: ClientId, input: QueuedInputs<I>) {
         match self.clients.entry(client) {
             Entry::Occupied(mut entry) => {
@@ -137,24 +582,108 @@ impl<I> ClientQueuedInputs<I> {
         for (_, queue) in &mut self.clients {
             queue.clean_old(current);
         }
+
+        for (_, queue) in &mut self.local_clients {
+            queue.clean_old(current);
+        }
     }
 
     pub fn retain(&mut self, buffer: i64) {
         for (_, queue) in &mut self.clients {
             queue.retain(buffer);
         }
+
+        for (_, queue) in &mut self.local_clients {
+            queue.retain(buffer);
+        }
+    }
+}
+
+/// Current inputs of split-screen players past the main player, which uses `Res<I>`.
+///
+/// `sub_ticks` are their equivalent of the `SubTick` resource.
+#[derive(Debug, Clone, Resource)]
+pub struct LocalInputs<I> {
+    pub inputs: BTreeMap<LocalIndex, I>,
+    pub sub_ticks: BTreeMap<LocalIndex, SubTick>,
+}
+
+impl<I> Default for LocalInputs<I> {
+    fn default() -> Self {
+        Self {
+            inputs: Default::default(),
+            sub_ticks: Default::default(),
+        }
+    }
+}
+
+impl<I> LocalInputs<I> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, index: LocalIndex) -> Option<&I> {
+        self.inputs.get(&index)
+    }
+
+    pub fn insert(&mut self, index: LocalIndex, input: I) {
+        self.inputs.insert(index, input);
+    }
+
+    pub fn sub_tick(&self, index: LocalIndex) -> SubTick {
+        self.sub_ticks.get(&index).cloned().unwrap_or_default()
+    }
+}
+
+/// Input buffers of split-screen players past the main player.
+#[derive(Debug, Clone, Resource)]
+pub struct LocalQueuedInputs<I> {
+    pub queues: BTreeMap<LocalIndex, QueuedInputs<I>>,
+}
+
+impl<I> Default for LocalQueuedInputs<I> {
+    fn default() -> Self {
+        Self {
+            queues: Default::default(),
+        }
+    }
+}
+
+impl<I> LocalQueuedInputs<I> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, index: LocalIndex) -> Option<&QueuedInputs<I>> {
+        self.queues.get(&index)
+    }
+
+    pub fn push(&mut self, index: LocalIndex, tick: NetworkTick, input: I) {
+        self.queues
+            .entry(index)
+            .or_insert_with(QueuedInputs::new)
+            .push(tick, input);
+    }
+
+    pub fn set_sub_tick(&mut self, index: LocalIndex, tick: NetworkTick, sub_tick: SubTick) {
+        if let Some(queue) = self.queues.get_mut(&index) {
+            queue.set_sub_tick(tick, sub_tick);
+        }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Resource)]
 pub struct QueuedInputs<I> {
     queue: BTreeMap<NetworkTick, I>,
+    /// Only filled in if the client is using `SubTickInputs`.
+    sub_ticks: BTreeMap<NetworkTick, SubTick>,
 }
 
 impl<I> QueuedInputs<I> {
     pub fn new() -> Self {
         Self {
             queue: Default::default(),
+            sub_ticks: Default::default(),
         }
     }
 
@@ -162,10 +691,61 @@ impl<I> QueuedInputs<I> {
         self.queue.get(tick)
     }
 
+    pub fn sub_tick(&self, tick: &NetworkTick) -> Option<SubTick> {
+        self.sub_ticks.get(tick).cloned()
+    }
+
+    /// Record how far into the tick an input was sampled, the input must already be queued.
+    pub fn set_sub_tick(&mut self, tick: NetworkTick, sub_tick: SubTick) {
+        if self.queue.contains_key(&tick) {
+            self.sub_ticks.insert(tick, sub_tick);
+        }
+    }
+
+    /// Drop sub-ticks for inputs that are gone.
+    fn clean_sub_ticks(&mut self) {
+        let queue = &self.queue;
+        self.sub_ticks.retain(|tick, _| queue.contains_key(tick));
+    }
+
+    /// Most recent input before `tick`.
+    pub fn last_before(&self, tick: &NetworkTick) -> Option<(&NetworkTick, &I)> {
+        self.queue.range(..*tick).next_back()
+    }
+
+    pub fn ticks(&self) -> impl Iterator<Item = &NetworkTick> {
+        self.queue.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Retain only the inputs that `keep` returns true for, inputs can be modified in place.
+    pub fn retain_inputs(&mut self, keep: impl FnMut(&NetworkTick, &mut I) -> bool) {
+        self.queue.retain(keep);
+        self.clean_sub_ticks();
+    }
+
+    /// Drop the oldest inputs until there are at most `len` left.
+    pub fn truncate_oldest(&mut self, len: usize) {
+        while self.queue.len() > len {
+            let oldest = *self.queue.keys().next().expect("queue is not empty");
+            self.queue.remove(&oldest);
+            self.sub_ticks.remove(&oldest);
+        }
+    }
+
     pub fn apply_buffer(&mut self, other: Self) {
         for (tick, input) in other.queue {
             self.upsert(tick, input);
         }
+
+        self.sub_ticks.extend(other.sub_ticks);
     }
 
     /// Upsert inputs, but reject inserting for previous ticks.
@@ -186,6 +766,7 @@ impl<I> QueuedInputs<I> {
     /// Clean any in the queue that are before the current tick.
     pub fn clean_old(&mut self, current: NetworkTick) {
         self.queue.retain(|tick, _| current.tick() >= tick.tick());
+        self.clean_sub_ticks();
     }
 
     /// Push an input into the queue
@@ -200,6 +781,7 @@ impl<I> QueuedInputs<I> {
 
         self.queue
             .retain(|tick, _| (newest.tick() as i64) - (tick.tick() as i64) < buffer);
+        self.clean_sub_ticks();
     }
 }
 
@@ -209,20 +791,77 @@ pub fn server_recv_input<I>(
     tick: Res<NetworkTick>,
     mut server: ResMut<RenetServer>,
     mut queued_inputs: ResMut<ClientQueuedInputs<I>>,
+    mut missing_inputs: ResMut<ClientMissingInputs>,
+    validation: Res<InputValidation<I>>,
+    mut violations: ResMut<ClientInputViolations>,
+    mut violation_events: EventWriter<InputViolationEvent>,
     mut acks: ResMut<ClientAcks>,
+    mut network_events: ResMut<ClientNetworkEvents>,
+    mut view_ticks: ResMut<ClientViewTicks>,
 ) where
     I: 'static + Send + Sync + Component + Clone + Default + Serialize + for<'de> Deserialize<'de>,
 {
     queued_inputs.retain(32);
+    missing_inputs.clean_old(*tick);
 
     for client_id in server.clients_id().into_iter() {
         while let Some(message) = server.receive_message(client_id, ClientChannel::Input.id()) {
-            let decompressed = zstd::bulk::decompress(&message.as_slice(), 10 * 1024).unwrap();
-            let input_message: ClientInputMessage<I> = bincode::deserialize(&decompressed).unwrap();
+            let mut report = |tick: Option<NetworkTick>, violation: InputViolation| {
+                violations.record(client_id, violation);
+                violation_events.send(InputViolationEvent {
+                    client_id,
+                    tick,
+                    violation,
+                });
+            };
+
+            let input_message = zstd::bulk::decompress(&message.as_slice(), 10 * 1024)
+                .ok()
+                .and_then(|decompressed| {
+                    bincode::deserialize::<ClientInputMessage<I>>(&decompressed).ok()
+                });
+            let mut input_message = match input_message {
+                Some(input_message) => input_message,
+                None => {
+                    report(None, InputViolation::Malformed);
+                    continue;
+                }
+            };
+
+            for (violation_tick, violation) in
+                validation.sanitize(client_id, *tick, &mut input_message.inputs)
+            {
+                report(violation_tick, violation);
+            }
+
+            for inputs in input_message.local_inputs.values_mut() {
+                for (violation_tick, violation) in validation.sanitize(client_id, *tick, inputs) {
+                    report(violation_tick, violation);
+                }
+            }
 
             recv_history.push(client_id, time.time_since_startup());
+            recv_history.push_margin(
+                client_id,
+                (input_message.tick.tick() as i64 - tick.tick() as i64) as i32,
+            );
             acks.apply_ack(client_id, &input_message.ack);
+            network_events.ack(client_id, input_message.event_ack);
+            if let Some(view_tick) = input_message.view_tick {
+                view_ticks.set(client_id, view_tick);
+            }
+
+            for input_tick in input_message.inputs.ticks() {
+                missing_inputs.arrived(client_id, 0, input_tick);
+            }
             queued_inputs.upsert(client_id, input_message.inputs);
+
+            for (index, inputs) in input_message.local_inputs {
+                for input_tick in inputs.ticks() {
+                    missing_inputs.arrived(client_id, index, input_tick);
+                }
+                queued_inputs.upsert_local(client_id, index, inputs);
+            }
         }
     }
 }
@@ -232,17 +871,111 @@ pub fn server_apply_input<I>(
     entities: &Entities,
     tick: Res<NetworkTick>,
     queued_inputs: Res<ClientQueuedInputs<I>>,
+    policy: Res<MissingInputPolicy<I>>,
+    mut missing_inputs: ResMut<ClientMissingInputs>,
     lobby: Res<Lobby>,
+    ownership: Res<Ownership>,
 ) where
     I: 'static + Send + Sync + Component + Clone + Default + Serialize + for<'de> Deserialize<'de>,
 {
-    for (client, entity) in lobby.players.iter() {
-        if let Some(input) = queued_inputs.get(*client, &tick) {
+    // Only what a client owns gets their input, including their own player.
+    for client in ownership.clients(*tick) {
+        let input = match queued_inputs.get(client, &tick) {
+            Some(input) => Some(input.clone()),
+            None => {
+                //error!("no input for player {} on tick {}", client, tick.tick());
+                missing_inputs.missed(client, 0, *tick);
+                policy.predict(*tick, queued_inputs.last(client, &tick))
+            }
+        };
+
+        if let Some(input) = input {
+            let targets = lobby.input_targets(client, ownership.owned(client, *tick));
+            let sub_tick = queued_inputs.sub_tick(client, &tick);
+
+            for entity in targets {
+                if entities.contains(entity) {
+                    let mut entity_commands = commands.entity(entity);
+                    entity_commands.insert(input.clone());
+                    match sub_tick {
+                        Some(sub_tick) => entity_commands.insert(sub_tick),
+                        None => entity_commands.remove::<SubTick>(),
+                    };
+                }
+            }
+        }
+    }
+
+    for ((client, index), entity) in lobby.local_players.iter() {
+        if ownership.owner(*entity, *tick) != Some(*client) {
+            continue;
+        }
+
+        let input = match queued_inputs.get_local(*client, *index, &tick) {
+            Some(input) => Some(input.clone()),
+            None => {
+                missing_inputs.missed(*client, *index, *tick);
+                policy.predict(*tick, queued_inputs.last_local(*client, *index, &tick))
+            }
+        };
+
+        if let Some(input) = input {
             if entities.contains(*entity) {
-                commands.entity(*entity).insert(input.clone());
+                let mut entity_commands = commands.entity(*entity);
+                entity_commands.insert(input);
+                match queued_inputs.sub_tick_local(*client, *index, &tick) {
+                    Some(sub_tick) => entity_commands.insert(sub_tick),
+                    None => entity_commands.remove::<SubTick>(),
+                };
+            }
+        }
+    }
+}
+
+pub fn server_relay_inputs<I>(
+    tick: Res<NetworkTick>,
+    relay: Res<RelayInputs>,
+    queued_inputs: Res<ClientQueuedInputs<I>>,
+    lobby: Res<Lobby>,
+    transforms: Query<&Transform>,
+    mut relayed: ResMut<ClientRelayedInputs>,
+) where
+    I: 'static + Send + Sync + Component + Clone + Default + Serialize + for<'de> Deserialize<'de>,
+{
+    let players = lobby
+        .iter_players()
+        .filter_map(|(player, entity)| {
+            let transform = transforms.get(entity).ok()?;
+            Some((player, entity, transform.translation))
+        })
+        .collect::<Vec<_>>();
+
+    for ((client_id, _), _, position) in players.iter() {
+        for ((other_id, index), entity, other_position) in players.iter() {
+            if other_id == client_id || !relay.in_range(*position, *other_position) {
+                continue;
+            }
+
+            let input = match index {
+                0 => queued_inputs
+                    .get(*other_id, &tick)
+                    .or_else(|| queued_inputs.last(*other_id, &tick).map(|(_, input)| input)),
+                _ => queued_inputs
+                    .get_local(*other_id, *index, &tick)
+                    .or_else(|| {
+                        queued_inputs
+                            .last_local(*other_id, *index, &tick)
+                            .map(|(_, input)| input)
+                    }),
+            };
+
+            if let Some(input) = input {
+                relayed.insert(
+                    *client_id,
+                    ServerEntity::from_entity(*entity),
+                    bincode::serialize(input).unwrap(),
+                );
             }
-        } else {
-            //error!("no input for player {} on tick {}", client, tick.tick());
         }
     }
 }
@@ -250,6 +983,10 @@ pub fn server_apply_input<I>(
 pub fn client_send_input<I>(
     tick: Res<NetworkTick>,
     input_buffer: Res<QueuedInputs<I>>,
+    local_buffers: Res<LocalQueuedInputs<I>>,
+    network_events: Res<ReceivedNetworkEvents>,
+    server_updates: Res<UpdateMessages>,
+    interpolation_clock: Option<Res<InterpolationClock>>,
     mut client: ResMut<RenetClient>,
 ) where
     I: 'static
@@ -269,10 +1006,26 @@ pub fn client_send_input<I>(
     let mut send_buffer = input_buffer.clone();
     send_buffer.retain(INPUT_SEND_BUFFER);
 
+    let mut local_inputs = local_buffers.queues.clone();
+    for (_, local_buffer) in &mut local_inputs {
+        local_buffer.retain(INPUT_SEND_BUFFER);
+    }
+
+    // Interpolated entities are shown behind the latest update we have.
+    let view_tick = match interpolation_clock {
+        Some(clock) if clock.lead.is_some() => {
+            Some(NetworkTick::new(clock.render_tick.max(0.0).floor() as u64))
+        }
+        _ => server_updates.latest().cloned(),
+    };
+
     let message = ClientInputMessage {
         tick: tick.clone(),
         ack: NetworkAck::new(tick.clone()),
+        event_ack: network_events.latest,
+        view_tick,
         inputs: send_buffer,
+        local_inputs,
     };
 
     let serialized = bincode::serialize(&message).unwrap();
@@ -282,10 +1035,60 @@ pub fn client_send_input<I>(
     client.send_message(ClientChannel::Input.id(), compressed);
 }
 
+/// Merge this frame's input into the input for the next tick, before the tick runs
+/// so it makes it in without waiting for another frame.
+pub fn client_accumulate_input<I>(
+    time: Res<Time>,
+    sim_info: Res<NetworkSimulationInfo>,
+    policy: Res<InputMergePolicy<I>>,
+    sub_ticks: Option<Res<SubTickInputs>>,
+    player_input: Res<I>,
+    local_inputs: Option<Res<LocalInputs<I>>>,
+    mut accumulated: ResMut<AccumulatedInputs<I>>,
+) where
+    I: 'static + Send + Sync + Clone + Serialize + Resource,
+{
+    policy.merge(&mut accumulated.input, &*player_input);
+
+    // The network stage hasn't added this frame's time yet, so add it here.
+    let overstep = sim_info.overstep() + time.delta_seconds_f64() / sim_info.step.as_secs_f64();
+    let sub_tick = SubTick::from_overstep(overstep.fract());
+    if sub_ticks.is_some() {
+        let sample = bincode::serialize(&*player_input).unwrap();
+        if accumulated.previous.as_ref() != Some(&sample) {
+            accumulated.sub_tick = Some(sub_tick);
+            accumulated.previous = Some(sample);
+        }
+    }
+
+    if let Some(local_inputs) = local_inputs {
+        for (index, input) in local_inputs.inputs.iter() {
+            let mut local_input = accumulated.local_inputs.remove(index);
+            policy.merge(&mut local_input, input);
+            if let Some(local_input) = local_input {
+                accumulated.local_inputs.insert(*index, local_input);
+            }
+
+            if sub_ticks.is_some() {
+                let sample = bincode::serialize(input).unwrap();
+                if accumulated.local_previous.get(index) != Some(&sample) {
+                    accumulated.local_sub_ticks.insert(*index, sub_tick);
+                    accumulated.local_previous.insert(*index, sample);
+                }
+            }
+        }
+    }
+}
+
 pub fn client_update_input_buffer<I>(
     tick: Res<NetworkTick>,
+    sim_info: Res<NetworkSimulationInfo>,
+    sub_ticks: Option<Res<SubTickInputs>>,
     player_input: Res<I>,
+    local_inputs: Option<Res<LocalInputs<I>>>,
+    mut accumulated: ResMut<AccumulatedInputs<I>>,
     mut input_buffer: ResMut<QueuedInputs<I>>,
+    mut local_buffers: ResMut<LocalQueuedInputs<I>>,
 ) where
     I: 'static
         + Send
@@ -299,13 +1102,71 @@ pub fn client_update_input_buffer<I>(
         + Resource,
 {
     //info!("recording {}: {:?}", tick.tick(), player_input.clone());
-    input_buffer.push(*tick, player_input.clone());
+    // Fall back to the current input if we've had more than one tick this frame.
+    let input = accumulated
+        .input
+        .take()
+        .unwrap_or_else(|| player_input.clone());
+    // Inputs that didn't change since the last tick have nothing better than now.
+    let recorded = SubTick::from_overstep(sim_info.overstep());
+    let sampled = accumulated.sub_tick.take();
+    let sub_tick = sub_ticks.as_ref().map(|_| sampled.unwrap_or(recorded));
+    input_buffer.push(*tick, input);
+    if let Some(sub_tick) = sub_tick {
+        input_buffer.set_sub_tick(*tick, sub_tick);
+    }
+
+    if let Some(local_inputs) = local_inputs {
+        for (index, input) in local_inputs.inputs.iter() {
+            let input = accumulated
+                .local_inputs
+                .remove(index)
+                .unwrap_or_else(|| input.clone());
+            let sampled = accumulated.local_sub_ticks.remove(index);
+            local_buffers.push(*index, *tick, input);
+            if sub_ticks.is_some() {
+                local_buffers.set_sub_tick(*index, *tick, sampled.unwrap_or(recorded));
+            }
+        }
+    }
+}
+
+/// Put the inputs the server relayed on remote players so they get predicted
+/// along with our own character.
+///
+/// Runs at the start of every tick, replays included, and keeps the last input we
+/// got for ticks the server hasn't sent us yet.
+pub fn client_apply_remote_inputs<I>(
+    mut commands: Commands,
+    entities: &Entities,
+    tick: Res<NetworkTick>,
+    server_updates: Res<UpdateMessages>,
+    server_entities: Res<ServerEntities>,
+) where
+    I: 'static + Send + Sync + Component + Clone + Default + Serialize + for<'de> Deserialize<'de>,
+{
+    for (server_entity, data) in server_updates.remote_inputs(&*tick) {
+        let input = match bincode::deserialize::<I>(data) {
+            Ok(input) => input,
+            Err(err) => {
+                error!("could not deserialize relayed input: {}", err);
+                continue;
+            }
+        };
+
+        if let Some(entity) = server_entities.get(entities, *server_entity) {
+            commands.entity(entity).insert((input, Predicted));
+        }
+    }
 }
 
 pub fn client_apply_input_buffer<I>(
     tick: Res<NetworkTick>,
     mut player_input: ResMut<I>,
+    mut sub_tick: ResMut<SubTick>,
+    local_inputs: Option<ResMut<LocalInputs<I>>>,
     input_buffer: Res<QueuedInputs<I>>,
+    local_buffers: Res<LocalQueuedInputs<I>>,
 ) where
     I: 'static
         + Send
@@ -321,7 +1182,215 @@ pub fn client_apply_input_buffer<I>(
     if let Some(input) = input_buffer.get(&*tick) {
         //info!("{}: {:?}", tick.tick(), input);
         *player_input = input.clone();
+        *sub_tick = input_buffer.sub_tick(&*tick).unwrap_or_default();
     } else {
         //error!("no input: {}", tick.tick());
     }
+
+    if let Some(mut local_inputs) = local_inputs {
+        for (index, queue) in local_buffers.queues.iter() {
+            if let Some(input) = queue.get(&*tick) {
+                local_inputs.insert(*index, input.clone());
+                let sub_tick = queue.sub_tick(&*tick).unwrap_or_default();
+                local_inputs.sub_ticks.insert(*index, sub_tick);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn missing_input_policy() {
+        let last_tick = NetworkTick::new(10);
+        let last = Some((&last_tick, &5u32));
+
+        let keep = MissingInputPolicy::<u32>::Keep;
+        assert_eq!(keep.predict(NetworkTick::new(11), last), None);
+
+        let repeat = MissingInputPolicy::<u32>::Repeat;
+        assert_eq!(repeat.predict(NetworkTick::new(11), last), Some(5));
+        assert_eq!(repeat.predict(NetworkTick::new(11), None), None);
+
+        let decay = MissingInputPolicy::<u32>::Decay {
+            ticks: 4,
+            blend: |from, to, t| (*from as f32 + (*to as f32 - *from as f32) * t).round() as u32,
+        };
+        assert_eq!(decay.predict(NetworkTick::new(11), last), Some(4));
+        assert_eq!(decay.predict(NetworkTick::new(12), last), Some(3));
+        assert_eq!(decay.predict(NetworkTick::new(14), last), Some(0));
+        assert_eq!(decay.predict(NetworkTick::new(20), last), Some(0));
+
+        let extrapolate =
+            MissingInputPolicy::<u32>::Extrapolate(|input, missed| input + missed as u32);
+        assert_eq!(extrapolate.predict(NetworkTick::new(13), last), Some(8));
+    }
+
+    #[test]
+    pub fn input_merge_policy() {
+        let mut accumulated = None;
+        let latest = InputMergePolicy::<u32>::Latest;
+        latest.merge(&mut accumulated, &1);
+        latest.merge(&mut accumulated, &2);
+        assert_eq!(accumulated, Some(2));
+
+        // Button pressed and released between ticks shouldn't be lost.
+        let mut accumulated = None;
+        let or = InputMergePolicy::<u32>::Merge(|accumulated, sample| *accumulated |= *sample);
+        or.merge(&mut accumulated, &0b01);
+        or.merge(&mut accumulated, &0b10);
+        or.merge(&mut accumulated, &0b00);
+        assert_eq!(accumulated, Some(0b11));
+    }
+
+    #[test]
+    pub fn sub_ticks() {
+        assert_eq!(SubTick::from_overstep(0.0), SubTick(0));
+        assert_eq!(SubTick::from_overstep(1.5), SubTick(u8::MAX));
+        assert_eq!(SubTick::from_overstep(0.5).fraction(), 128.0 / 255.0);
+
+        let mut inputs = QueuedInputs::new();
+        inputs.set_sub_tick(NetworkTick::new(1), SubTick(10));
+        assert_eq!(inputs.sub_tick(&NetworkTick::new(1)), None);
+
+        inputs.upsert(NetworkTick::new(1), 1u32);
+        inputs.upsert(NetworkTick::new(2), 2u32);
+        inputs.set_sub_tick(NetworkTick::new(1), SubTick(10));
+        assert_eq!(inputs.sub_tick(&NetworkTick::new(1)), Some(SubTick(10)));
+
+        // Sub-ticks go along with their inputs.
+        inputs.truncate_oldest(1);
+        assert_eq!(inputs.sub_tick(&NetworkTick::new(1)), None);
+
+        // Split-screen players keep their own.
+        let mut local_buffers = LocalQueuedInputs::new();
+        local_buffers.push(1, NetworkTick::new(2), 2u32);
+        local_buffers.set_sub_tick(1, NetworkTick::new(2), SubTick(20));
+
+        let mut queued_inputs = ClientQueuedInputs::new();
+        queued_inputs.upsert(1, inputs);
+        queued_inputs.upsert_local(1, 1, local_buffers.get(1).unwrap().clone());
+        let tick = NetworkTick::new(2);
+        assert_eq!(queued_inputs.sub_tick(1, &tick), None);
+        assert_eq!(queued_inputs.sub_tick_local(1, 1, &tick), Some(SubTick(20)));
+    }
+
+    #[test]
+    pub fn client_missing_inputs() {
+        let mut missing = ClientMissingInputs::new();
+        missing.missed(1, 0, NetworkTick::new(10));
+        missing.missed(1, 1, NetworkTick::new(10));
+        missing.missed(1, 1, NetworkTick::new(11));
+        missing.arrived(1, 1, &NetworkTick::new(10));
+
+        // Split-screen players shouldn't count towards the main player.
+        assert_eq!(missing.get(1, 0).map(|history| history.missing), Some(1));
+        assert_eq!(missing.get(1, 0).map(|history| history.late), Some(0));
+        assert_eq!(missing.get(1, 1).map(|history| history.missing), Some(2));
+        assert_eq!(missing.get(1, 1).map(|history| history.late), Some(1));
+
+        missing.remove(&1);
+        assert_eq!(missing.iter().count(), 0);
+    }
+
+    #[test]
+    pub fn missing_input_history() {
+        let mut history = MissingInputHistory::new();
+        history.missed(NetworkTick::new(10));
+        history.missed(NetworkTick::new(10));
+        history.missed(NetworkTick::new(11));
+        assert_eq!(history.missing, 2);
+
+        history.arrived(&NetworkTick::new(11));
+        history.arrived(&NetworkTick::new(11));
+        assert_eq!(history.late, 1);
+
+        // Too old to be late anymore, it was just lost.
+        history.clean_old(NetworkTick::new(10 + INPUT_RETAIN_BUFFER as u64));
+        history.arrived(&NetworkTick::new(10));
+        assert_eq!(history.late, 1);
+    }
+
+    #[derive(Component, Resource, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    struct TestInput(u32);
+
+    #[test]
+    pub fn relay_nearby_inputs() {
+        let mut world = World::new();
+        let tick = NetworkTick::new(10);
+        world.insert_resource(tick);
+        world.insert_resource(RelayInputs { radius: 10.0 });
+        world.insert_resource(ClientRelayedInputs::new());
+
+        let mut lobby = Lobby::new();
+        let mut queued_inputs = ClientQueuedInputs::<TestInput>::new();
+        let mut players = Vec::new();
+        for (client_id, x) in [(1, 0.0), (2, 5.0), (3, 100.0)] {
+            let entity = world.spawn(Transform::from_xyz(x, 0.0, 0.0)).id();
+            lobby.insert_player(client_id, 0, entity);
+            players.push(ServerEntity::from_entity(entity));
+
+            let mut inputs = QueuedInputs::new();
+            inputs.upsert(tick, TestInput(client_id as u32));
+            queued_inputs.upsert(client_id, inputs);
+        }
+        world.insert_resource(lobby);
+        world.insert_resource(queued_inputs);
+
+        let mut stage = SystemStage::single(server_relay_inputs::<TestInput>);
+        stage.run(&mut world);
+
+        let mut relayed = world.resource_mut::<ClientRelayedInputs>();
+        let relayed_to = |relayed: &mut ClientRelayedInputs, client_id| {
+            relayed
+                .take(&client_id)
+                .into_iter()
+                .map(|(server_entity, data)| {
+                    (
+                        server_entity,
+                        bincode::deserialize::<TestInput>(&data).unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            relayed_to(&mut relayed, 1),
+            vec![(players[1], TestInput(2))]
+        );
+        assert_eq!(
+            relayed_to(&mut relayed, 2),
+            vec![(players[0], TestInput(1))]
+        );
+        // Too far from everyone else.
+        assert!(!relayed.has_pending(&3));
+    }
+
+    #[test]
+    pub fn sub_tick_on_change() {
+        let step = tick_hz(10);
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        world.insert_resource(NetworkSimulationInfo::new(step));
+        world.insert_resource(InputMergePolicy::<TestInput>::Latest);
+        world.insert_resource(SubTickInputs);
+        world.insert_resource(TestInput(1));
+        world.insert_resource(AccumulatedInputs::<TestInput>::new());
+
+        let mut pre_update = SystemStage::single(client_accumulate_input::<TestInput>);
+        let mut frame = |world: &mut World, fraction: f64, input: u32| {
+            world.resource_mut::<NetworkSimulationInfo>().accumulator = step.mul_f64(fraction);
+            *world.resource_mut::<TestInput>() = TestInput(input);
+            pre_update.run(world);
+            world.resource::<AccumulatedInputs<TestInput>>().sub_tick
+        };
+
+        // Pressed a quarter of the way in, held for the rest of the tick.
+        let pressed = SubTick::from_overstep(0.25);
+        assert_eq!(frame(&mut world, 0.25, 2), Some(pressed));
+        assert_eq!(frame(&mut world, 0.5, 2), Some(pressed));
+        assert_eq!(frame(&mut world, 0.75, 2), Some(pressed));
+        assert_eq!(frame(&mut world, 0.8, 3), Some(SubTick::from_overstep(0.8)));
+    }
 }
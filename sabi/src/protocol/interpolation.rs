@@ -0,0 +1,272 @@
+use std::{collections::BTreeMap, marker::PhantomData};
+
+use bevy::prelude::*;
+use iyes_loopless::prelude::IntoConditionalSystem;
+
+use crate::{prelude::*, stage::NetworkSimulationInfo};
+
+use super::update::UpdateMessages;
+
+/// How many ticks of server state we keep around for interpolating.
+pub const INTERPOLATION_BUFFER: u64 = 32;
+
+/// Render this entity between server updates instead of predicting it.
+///
+/// Interpolated entities aren't `Predicted`, so they are never rewound.
+#[derive(Default, Debug, Clone, Copy, Component)]
+pub struct Interpolated;
+
+/// Blend between two states of a component.
+pub trait Interpolate {
+    fn interpolate(&self, other: &Self, t: f32) -> Self;
+}
+
+impl Interpolate for Transform {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        Transform {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale.lerp(other.scale, t),
+        }
+    }
+}
+
+/// Where the client is rendering interpolated entities.
+#[derive(Debug, Clone, Resource)]
+pub struct InterpolationClock {
+    /// Ticks behind the latest server update to render at.
+    pub delay: f64,
+    /// Ticks the client is ahead of the latest server update, smoothed out.
+    pub lead: Option<f64>,
+    /// Fractional tick interpolated entities are rendered at this frame.
+    pub render_tick: f64,
+}
+
+impl Default for InterpolationClock {
+    fn default() -> Self {
+        Self {
+            delay: 2.0,
+            lead: None,
+            render_tick: 0.0,
+        }
+    }
+}
+
+impl InterpolationClock {
+    pub fn new(delay: f64) -> Self {
+        Self {
+            delay,
+            ..Default::default()
+        }
+    }
+
+    /// Move the render tick along for this frame.
+    pub fn update(&mut self, tick: NetworkTick, overstep: f64, latest: NetworkTick) {
+        let target = tick.tick() as f64 - latest.tick() as f64;
+        let lead = match self.lead {
+            // Too far off to smooth it out, probably a hitch.
+            Some(lead) if (target - lead).abs() > 4.0 => target,
+            Some(lead) => lead + (target - lead) * 0.1,
+            None => target,
+        };
+
+        self.lead = Some(lead);
+        self.render_tick = tick.tick() as f64 + overstep - lead - self.delay;
+    }
+}
+
+/// Server states of a component received for an interpolated entity.
+#[derive(Debug, Clone, Component)]
+pub struct InterpolationBuffer<C> {
+    states: BTreeMap<NetworkTick, C>,
+}
+
+impl<C> Default for InterpolationBuffer<C> {
+    fn default() -> Self {
+        Self {
+            states: Default::default(),
+        }
+    }
+}
+
+impl<C> InterpolationBuffer<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, tick: NetworkTick, state: C) {
+        self.states.insert(tick, state);
+
+        let newest = self.states.keys().max().cloned().unwrap_or_default();
+        self.states
+            .retain(|tick, _| newest.tick() - tick.tick() < INTERPOLATION_BUFFER);
+    }
+}
+
+impl<C> InterpolationBuffer<C>
+where
+    C: Interpolate + Clone,
+{
+    /// State at a fractional tick, holding the nearest state if we are outside the buffer.
+    pub fn sample(&self, render_tick: f64) -> Option<C> {
+        let floor = NetworkTick::new(render_tick.max(0.0).floor() as u64);
+        let before = self.states.range(..=floor).next_back();
+        let after = self
+            .states
+            .range(NetworkTick::new(floor.tick() + 1)..)
+            .next();
+
+        match (before, after) {
+            (Some((from_tick, from)), Some((to_tick, to))) => {
+                let span = (to_tick.tick() - from_tick.tick()) as f64;
+                let t = (render_tick - from_tick.tick() as f64) / span;
+                Some(from.interpolate(to, t.clamp(0.0, 1.0) as f32))
+            }
+            (Some((_, state)), None) | (None, Some((_, state))) => Some(state.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Registers `C` to be interpolated on `Interpolated` entities.
+pub struct InterpolatePlugin<C>(PhantomData<C>);
+
+impl<C> Default for InterpolatePlugin<C> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<C> Plugin for InterpolatePlugin<C>
+where
+    C: 'static + Send + Sync + Component + Replicate + Interpolate + Clone,
+{
+    fn build(&self, app: &mut App) {
+        if !app.world.contains_resource::<crate::Client>() {
+            return;
+        }
+
+        if !app.world.contains_resource::<InterpolationClock>() {
+            app.insert_resource(InterpolationClock::default());
+            app.add_system_to_stage(
+                CoreStage::PostUpdate,
+                client_interpolation_clock
+                    .run_if_resource_exists::<NetworkTick>()
+                    .label("client_interpolation_clock"),
+            );
+        }
+
+        app.add_system_to_stage(CoreStage::PreUpdate, client_setup_interpolation::<C>);
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            client_interpolate::<C>
+                .after("client_interpolation_clock")
+                .before(bevy::transform::TransformSystem::TransformPropagate),
+        );
+    }
+}
+
+pub trait InterpolateAppExt {
+    /// Interpolate `C` on `Interpolated` entities instead of predicting it.
+    fn add_interpolation<C>(&mut self) -> &mut Self
+    where
+        C: 'static + Send + Sync + Component + Replicate + Interpolate + Clone;
+}
+
+impl InterpolateAppExt for App {
+    fn add_interpolation<C>(&mut self) -> &mut Self
+    where
+        C: 'static + Send + Sync + Component + Replicate + Interpolate + Clone,
+    {
+        self.add_plugin(InterpolatePlugin::<C>::default())
+    }
+}
+
+pub fn client_interpolation_clock(
+    tick: Res<NetworkTick>,
+    sim_info: Res<NetworkSimulationInfo>,
+    server_updates: Res<UpdateMessages>,
+    mut clock: ResMut<InterpolationClock>,
+) {
+    if let Some(latest) = server_updates.latest() {
+        clock.update(*tick, sim_info.overstep(), *latest);
+    }
+}
+
+pub fn client_setup_interpolation<C>(
+    mut commands: Commands,
+    added: Query<Entity, (With<Interpolated>, Without<InterpolationBuffer<C>>)>,
+    removed: Query<Entity, (Without<Interpolated>, With<InterpolationBuffer<C>>)>,
+) where
+    C: 'static + Send + Sync + Component + Interpolate + Clone,
+{
+    for entity in added.iter() {
+        commands
+            .entity(entity)
+            .insert(InterpolationBuffer::<C>::new());
+    }
+
+    for entity in removed.iter() {
+        commands.entity(entity).remove::<InterpolationBuffer<C>>();
+    }
+}
+
+pub fn client_interpolate<C>(
+    mut commands: Commands,
+    clock: Res<InterpolationClock>,
+    mut query: Query<(Entity, &InterpolationBuffer<C>, Option<&mut C>), With<Interpolated>>,
+) where
+    C: 'static + Send + Sync + Component + Interpolate + Clone,
+{
+    for (entity, buffer, component) in query.iter_mut() {
+        let state = match buffer.sample(clock.render_tick) {
+            Some(state) => state,
+            None => continue,
+        };
+
+        match component {
+            Some(mut component) => *component = state,
+            None => {
+                commands.entity(entity).insert(state);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn sample() {
+        let mut buffer = InterpolationBuffer::new();
+        assert!(buffer.sample(10.0).is_none());
+
+        buffer.push(NetworkTick::new(10), Transform::from_xyz(0.0, 0.0, 0.0));
+        buffer.push(NetworkTick::new(14), Transform::from_xyz(4.0, 0.0, 0.0));
+
+        let sampled = buffer.sample(11.0).unwrap();
+        assert_eq!(sampled.translation.x, 1.0);
+
+        // Hold the nearest state outside of the buffer.
+        assert_eq!(buffer.sample(8.0).unwrap().translation.x, 0.0);
+        assert_eq!(buffer.sample(20.0).unwrap().translation.x, 4.0);
+
+        buffer.push(
+            NetworkTick::new(14 + INTERPOLATION_BUFFER - 1),
+            Transform::default(),
+        );
+        assert_eq!(buffer.sample(11.0).unwrap().translation.x, 4.0);
+    }
+
+    #[test]
+    pub fn clock() {
+        let mut clock = InterpolationClock::new(2.0);
+        clock.update(NetworkTick::new(20), 0.5, NetworkTick::new(16));
+        assert_eq!(clock.render_tick, 14.5);
+
+        // Small changes in lead get smoothed out.
+        clock.update(NetworkTick::new(21), 0.0, NetworkTick::new(16));
+        assert!(clock.render_tick > 14.5 && clock.render_tick < 15.0);
+    }
+}
@@ -0,0 +1,302 @@
+use std::{collections::VecDeque, marker::PhantomData};
+
+use bevy::{ecs::system::SystemParam, prelude::*, utils::HashMap};
+use bevy_rapier3d::{parry::query, prelude::*, rapier::math::Isometry};
+
+use crate::prelude::*;
+
+/// Record this entity's collider so hit checks can be run against where
+/// clients saw it.
+///
+/// Static geometry doesn't move, so query the live world for that instead.
+#[derive(Default, Debug, Clone, Copy, Component)]
+pub struct LagCompensated;
+
+/// How far back hit checks can look.
+#[derive(Debug, Clone, Resource)]
+pub struct LagCompensationSettings {
+    /// Ticks of collider history to keep.
+    pub history: u64,
+    /// Most ticks we will rewind for a client, so high latency players can't
+    /// hit things that have long since moved.
+    pub max_rewind: u64,
+}
+
+impl Default for LagCompensationSettings {
+    fn default() -> Self {
+        Self {
+            history: 32,
+            max_rewind: 16,
+        }
+    }
+}
+
+/// Collider of a `LagCompensated` entity on a past tick.
+#[derive(Debug, Clone)]
+pub struct HistoricCollider {
+    pub entity: Entity,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub collider: Collider,
+}
+
+#[derive(Default, Debug, Clone, Resource)]
+pub struct ColliderHistory {
+    ticks: VecDeque<(NetworkTick, Vec<HistoricCollider>)>,
+}
+
+impl ColliderHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, tick: NetworkTick, colliders: Vec<HistoricCollider>, history: u64) {
+        self.ticks.push_back((tick, colliders));
+
+        while self.ticks.len() as u64 > history {
+            self.ticks.pop_front();
+        }
+    }
+
+    /// Colliders on `tick`, or the oldest we have if it's further back than that.
+    pub fn get(&self, tick: &NetworkTick) -> Option<&[HistoricCollider]> {
+        self.ticks
+            .iter()
+            .rev()
+            .find(|(history_tick, _)| history_tick <= tick)
+            .or_else(|| self.ticks.front())
+            .map(|(_, colliders)| colliders.as_slice())
+    }
+}
+
+/// Which tick each client was looking at, from their input messages.
+#[derive(Default, Debug, Clone, Resource)]
+pub struct ClientViewTicks {
+    clients: HashMap<ClientId, NetworkTick>,
+}
+
+impl ClientViewTicks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, client_id: ClientId, tick: NetworkTick) {
+        self.clients.insert(client_id, tick);
+    }
+
+    pub fn get(&self, client_id: &ClientId) -> Option<NetworkTick> {
+        self.clients.get(client_id).cloned()
+    }
+
+    pub fn remove(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+/// Run hit checks against the world as a client saw it.
+#[derive(SystemParam)]
+pub struct LagCompensation<'w, 's> {
+    tick: Res<'w, NetworkTick>,
+    settings: Res<'w, LagCompensationSettings>,
+    history: Res<'w, ColliderHistory>,
+    view_ticks: Res<'w, ClientViewTicks>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> LagCompensation<'w, 's> {
+    /// Tick `client_id` was seeing, no further back than the max rewind.
+    pub fn view_tick(&self, client_id: ClientId) -> NetworkTick {
+        let oldest = self.tick.tick().saturating_sub(self.settings.max_rewind);
+        let view_tick = self
+            .view_ticks
+            .get(&client_id)
+            .map(|tick| tick.tick())
+            .unwrap_or(self.tick.tick());
+
+        NetworkTick::new(view_tick.clamp(oldest, self.tick.tick()))
+    }
+
+    /// Colliders as `client_id` saw them.
+    pub fn colliders(&self, client_id: ClientId) -> &[HistoricCollider] {
+        self.history
+            .get(&self.view_tick(client_id))
+            .unwrap_or_default()
+    }
+
+    /// Closest collider hit by a ray and the time of impact.
+    pub fn cast_ray(
+        &self,
+        client_id: ClientId,
+        ray_origin: Vec3,
+        ray_dir: Vec3,
+        max_toi: f32,
+        solid: bool,
+        filter: impl Fn(Entity) -> bool,
+    ) -> Option<(Entity, f32)> {
+        self.colliders(client_id)
+            .iter()
+            .filter(|historic| filter(historic.entity))
+            .filter_map(|historic| {
+                historic
+                    .collider
+                    .cast_ray(
+                        historic.translation,
+                        historic.rotation,
+                        ray_origin,
+                        ray_dir,
+                        max_toi,
+                        solid,
+                    )
+                    .map(|toi| (historic.entity, toi))
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+    }
+
+    /// Colliders intersecting `shape`.
+    pub fn intersect_shape(
+        &self,
+        client_id: ClientId,
+        translation: Vec3,
+        rotation: Quat,
+        shape: &Collider,
+        filter: impl Fn(Entity) -> bool,
+    ) -> Vec<Entity> {
+        let shape_pos: Isometry<f32> = (translation, rotation).into();
+
+        self.colliders(client_id)
+            .iter()
+            .filter(|historic| filter(historic.entity))
+            .filter(|historic| {
+                let pos: Isometry<f32> = (historic.translation, historic.rotation).into();
+                query::intersection_test(&shape_pos, &*shape.raw, &pos, &*historic.collider.raw)
+                    .unwrap_or(false)
+            })
+            .map(|historic| historic.entity)
+            .collect()
+    }
+}
+
+/// Where `entity` is this tick.
+///
+/// This runs before `GlobalTransform` is propagated in `PostUpdate`, so that would
+/// still be from the tick before.
+fn current_transform(
+    entity: Entity,
+    transforms: &Query<(&Transform, Option<&Parent>)>,
+) -> GlobalTransform {
+    let mut global = GlobalTransform::IDENTITY;
+    let mut next = Some(entity);
+    while let Some(entity) = next {
+        match transforms.get(entity) {
+            Ok((transform, parent)) => {
+                global = GlobalTransform::from(*transform) * global;
+                next = parent.map(|parent| parent.get());
+            }
+            Err(_) => break,
+        }
+    }
+
+    global
+}
+
+pub fn server_record_collider_history(
+    tick: Res<NetworkTick>,
+    settings: Res<LagCompensationSettings>,
+    mut history: ResMut<ColliderHistory>,
+    query: Query<(Entity, &Collider), With<LagCompensated>>,
+    transforms: Query<(&Transform, Option<&Parent>)>,
+) {
+    let colliders = query
+        .iter()
+        .map(|(entity, collider)| {
+            let (_, rotation, translation) =
+                current_transform(entity, &transforms).to_scale_rotation_translation();
+            HistoricCollider {
+                entity,
+                translation,
+                rotation,
+                collider: collider.clone(),
+            }
+        })
+        .collect();
+
+    history.push(*tick, colliders, settings.history);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ball(entity: u32, x: f32) -> HistoricCollider {
+        HistoricCollider {
+            entity: Entity::from_raw(entity),
+            translation: Vec3::new(x, 0.0, 0.0),
+            rotation: Quat::IDENTITY,
+            collider: Collider::ball(0.5),
+        }
+    }
+
+    #[test]
+    pub fn collider_history() {
+        let mut history = ColliderHistory::new();
+        for tick in 0..10 {
+            history.push(NetworkTick::new(tick), vec![ball(0, tick as f32)], 4);
+        }
+
+        let x = |tick| {
+            history.get(&NetworkTick::new(tick)).unwrap()[0]
+                .translation
+                .x
+        };
+        assert_eq!(x(8), 8.0);
+        assert_eq!(x(20), 9.0);
+        // Older than we keep, so the oldest is as close as it gets.
+        assert_eq!(x(2), 6.0);
+
+        let hit = history.get(&NetworkTick::new(7)).unwrap()[0]
+            .collider
+            .cast_ray(
+                Vec3::new(7.0, 0.0, 0.0),
+                Quat::IDENTITY,
+                Vec3::new(7.0, 0.0, -5.0),
+                Vec3::Z,
+                10.0,
+                true,
+            );
+        assert!(hit.is_some());
+    }
+
+    #[test]
+    pub fn record_current_transform() {
+        let mut world = World::new();
+        world.insert_resource(NetworkTick::new(10));
+        world.insert_resource(LagCompensationSettings::default());
+        world.insert_resource(ColliderHistory::new());
+
+        // Moved this tick, so its `GlobalTransform` is stale until `PostUpdate`.
+        let parent = world
+            .spawn((
+                Transform::from_xyz(5.0, 0.0, 0.0),
+                GlobalTransform::default(),
+            ))
+            .id();
+        let child = world
+            .spawn((
+                Transform::from_xyz(0.0, 2.0, 0.0),
+                GlobalTransform::default(),
+                Collider::ball(0.5),
+                LagCompensated,
+            ))
+            .id();
+        world.entity_mut(parent).push_children(&[child]);
+
+        let mut stage = SystemStage::single(server_record_collider_history);
+        stage.run(&mut world);
+
+        let history = world.resource::<ColliderHistory>();
+        let colliders = history.get(&NetworkTick::new(10)).unwrap();
+        assert_eq!(colliders[0].entity, child);
+        assert_eq!(colliders[0].translation, Vec3::new(5.0, 2.0, 0.0));
+    }
+}
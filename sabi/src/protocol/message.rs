@@ -0,0 +1,504 @@
+use std::marker::PhantomData;
+
+use bevy::{ecs::entity::Entities, prelude::*, utils::HashMap};
+use bevy_renet::renet::{RenetClient, RenetServer, ServerEvent};
+use iyes_loopless::prelude::IntoConditionalSystem;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    prelude::*,
+    protocol::{
+        command::{register_message_id, MessageId},
+        pending::{PendingEvents, PendingEventsAppExt},
+        resim::ResourceSnapshotBuffer,
+        spawn::{adopt_predicted_spawn, AdoptedSpawns},
+    },
+    stage::{NetworkSimulationAppExt, RequestRewind},
+};
+
+/// A player was announced by the server, `entity` is the local entity for their character.
+#[derive(Debug, Clone)]
+pub struct PlayerConnected {
+    pub id: ClientId,
+    pub index: LocalIndex,
+    pub entity: Entity,
+}
+
+/// A player has left the server.
+#[derive(Debug, Clone)]
+pub struct PlayerDisconnected {
+    pub id: ClientId,
+    pub index: LocalIndex,
+}
+
+/// The server has given us control over this entity starting at `tick`.
+#[derive(Debug, Clone)]
+pub struct OwnershipAssigned {
+    pub entity: Entity,
+    pub tick: NetworkTick,
+}
+
+/// The server has taken control of this entity away from us starting at `tick`.
+#[derive(Debug, Clone)]
+pub struct OwnershipRevoked {
+    pub entity: Entity,
+    pub tick: NetworkTick,
+}
+
+/// Which clients a message should go to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageTarget {
+    Client(ClientId),
+    Clients(Vec<ClientId>),
+    All,
+    AllExcept(ClientId),
+}
+
+/// Send a registered message to clients, `tick` lets the clients hold onto it until they reach it.
+#[derive(Debug, Clone)]
+pub struct SendServerMessage<T> {
+    pub target: MessageTarget,
+    pub tick: Option<NetworkTick>,
+    pub message: T,
+}
+
+impl<T> SendServerMessage<T> {
+    pub fn new(target: MessageTarget, message: T) -> Self {
+        Self {
+            target,
+            tick: None,
+            message,
+        }
+    }
+
+    pub fn at_tick(mut self, tick: NetworkTick) -> Self {
+        self.tick = Some(tick);
+        self
+    }
+}
+
+/// Registered message received by a client from the server.
+#[derive(Debug, Clone)]
+pub struct ServerMessageEvent<T> {
+    pub tick: Option<NetworkTick>,
+    pub message: T,
+}
+
+impl ServerMessage {
+    pub fn send(&self, server: &mut RenetServer, client_id: ClientId) {
+        let serialized = bincode::serialize(self).unwrap();
+        server.send_message(client_id, ServerChannel::Message.id(), serialized);
+    }
+
+    pub fn broadcast(&self, server: &mut RenetServer) {
+        let serialized = bincode::serialize(self).unwrap();
+        server.broadcast_message(ServerChannel::Message.id(), serialized);
+    }
+
+    pub fn send_to(&self, server: &mut RenetServer, target: &MessageTarget) {
+        let serialized = bincode::serialize(self).unwrap();
+        let channel_id = ServerChannel::Message.id();
+        match target {
+            MessageTarget::Client(client_id) => {
+                server.send_message(*client_id, channel_id, serialized);
+            }
+            MessageTarget::Clients(client_ids) => {
+                for client_id in client_ids {
+                    server.send_message(*client_id, channel_id, serialized.clone());
+                }
+            }
+            MessageTarget::All => {
+                server.broadcast_message(channel_id, serialized);
+            }
+            MessageTarget::AllExcept(client_id) => {
+                server.broadcast_message_except(*client_id, channel_id, serialized);
+            }
+        }
+    }
+}
+
+/// Registered messages received that haven't been turned into events yet.
+#[derive(Default, Debug, Clone, Resource)]
+pub struct ReceivedServerMessages {
+    messages: HashMap<u64, Vec<(Option<NetworkTick>, Vec<u8>)>>,
+}
+
+impl ReceivedServerMessages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, id: u64) {
+        self.messages.entry(id).or_default();
+    }
+
+    pub fn push(&mut self, id: u64, tick: Option<NetworkTick>, data: Vec<u8>) -> bool {
+        match self.messages.get_mut(&id) {
+            Some(messages) => {
+                messages.push((tick, data));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take any messages that are ready by `current`, leaving those meant for later ticks.
+    pub fn drain_ready(
+        &mut self,
+        id: u64,
+        current: Option<NetworkTick>,
+    ) -> Vec<(Option<NetworkTick>, Vec<u8>)> {
+        let messages = match self.messages.get_mut(&id) {
+            Some(messages) => messages,
+            None => return Vec::new(),
+        };
+
+        let (ready, later): (Vec<_>, Vec<_>) =
+            messages
+                .drain(..)
+                .partition(|(tick, _)| match (tick, current) {
+                    (Some(tick), Some(current)) => *tick <= current,
+                    _ => true,
+                });
+
+        *messages = later;
+        ready
+    }
+}
+
+/// Registers `T` as a message the server can send to clients.
+///
+/// `name` identifies it over the network, so it has to match on the client and server.
+pub struct ServerMessagePlugin<T> {
+    pub name: &'static str,
+    marker: PhantomData<T>,
+}
+
+impl<T> ServerMessagePlugin<T> {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Plugin for ServerMessagePlugin<T>
+where
+    T: 'static + Send + Sync + Clone + Serialize + DeserializeOwned,
+{
+    fn build(&self, app: &mut App) {
+        let id = register_message_id::<T>(app, self.name);
+
+        if app.world.contains_resource::<crate::Server>() {
+            app.add_pending_events::<SendServerMessage<T>>();
+            app.add_meta_network_system(
+                server_send_registered_messages::<T>
+                    .run_if_resource_exists::<RenetServer>()
+                    .after("server_send_messages")
+                    .after("hold_pending_events"),
+            );
+        }
+
+        if app.world.contains_resource::<crate::Client>() {
+            app.add_event::<ServerMessageEvent<T>>();
+            if !app.world.contains_resource::<ReceivedServerMessages>() {
+                app.insert_resource(ReceivedServerMessages::new());
+            }
+            app.world
+                .resource_mut::<ReceivedServerMessages>()
+                .register(id);
+            app.add_meta_network_system(
+                client_dispatch_messages::<T>.after("client_recv_messages"),
+            );
+        }
+    }
+}
+
+pub trait ServerMessageAppExt {
+    /// Register `T` as a message the server can send to clients under `name`.
+    fn add_server_message<T>(&mut self, name: &'static str) -> &mut Self
+    where
+        T: 'static + Send + Sync + Clone + Serialize + DeserializeOwned;
+}
+
+impl ServerMessageAppExt for App {
+    fn add_server_message<T>(&mut self, name: &'static str) -> &mut Self
+    where
+        T: 'static + Send + Sync + Clone + Serialize + DeserializeOwned,
+    {
+        self.add_plugin(ServerMessagePlugin::<T>::new(name))
+    }
+}
+
+pub fn server_send_registered_messages<T>(
+    id: Res<MessageId<T>>,
+    mut pending: ResMut<PendingEvents<SendServerMessage<T>>>,
+    mut server: ResMut<RenetServer>,
+) where
+    T: 'static + Send + Sync + Serialize,
+{
+    for (_, message) in pending.drain() {
+        ServerMessage::Custom {
+            id: id.id,
+            tick: message.tick,
+            data: bincode::serialize(&message.message).unwrap(),
+        }
+        .send_to(&mut server, &message.target);
+    }
+}
+
+pub fn client_dispatch_messages<T>(
+    id: Res<MessageId<T>>,
+    tick: Option<Res<NetworkTick>>,
+    mut received: ResMut<ReceivedServerMessages>,
+    mut events: EventWriter<ServerMessageEvent<T>>,
+) where
+    T: 'static + Send + Sync + DeserializeOwned,
+{
+    let current = tick.map(|tick| *tick);
+    for (message_tick, data) in received.drain_ready(id.id, current) {
+        match bincode::deserialize::<T>(&data) {
+            Ok(message) => events.send(ServerMessageEvent {
+                tick: message_tick,
+                message,
+            }),
+            Err(err) => error!(
+                "could not deserialize {}: {}",
+                std::any::type_name::<T>(),
+                err
+            ),
+        }
+    }
+}
+
+/// Keep clients informed about who they are, which players are in the `Lobby`
+/// and what entities they own.
+///
+/// `announced` is what we have already told the clients about, so we only send
+/// the differences whenever the lobby changes.
+pub fn server_send_messages(
+    tick: Res<NetworkTick>,
+    entities: &Entities,
+    mut server_events: EventReader<ServerEvent>,
+    mut lobby: ResMut<Lobby>,
+    mut ownership: ResMut<Ownership>,
+    rng: Res<NetworkRng>,
+    mut announced: Local<HashMap<(ClientId, LocalIndex), Entity>>,
+    mut server: ResMut<RenetServer>,
+) {
+    for event in server_events.iter() {
+        match event {
+            ServerEvent::ClientConnected(client_id, _user_data) => {
+                ServerMessage::SetPlayer { id: *client_id }.send(&mut server, *client_id);
+                ServerMessage::SetRngSeed { seed: rng.seed() }.send(&mut server, *client_id);
+
+                // Catch the new client up on everyone that is already here.
+                for ((id, index), entity) in announced.iter() {
+                    ServerMessage::PlayerConnected {
+                        id: *id,
+                        index: *index,
+                        entity: ServerEntity::from_entity(*entity),
+                    }
+                    .send(&mut server, *client_id);
+                }
+            }
+            ServerEvent::ClientDisconnected(client_id) => {
+                lobby.remove(client_id);
+                ownership.disconnect(*client_id);
+            }
+        }
+    }
+
+    let disconnected = announced
+        .keys()
+        .filter(|(id, index)| lobby.player(*id, *index).is_none())
+        .cloned()
+        .collect::<Vec<_>>();
+    for (id, index) in disconnected {
+        announced.remove(&(id, index));
+        ServerMessage::PlayerDisconnected { id, index }.broadcast(&mut server);
+    }
+
+    for ((id, index), entity) in lobby.iter_players() {
+        if announced.get(&(id, index)) == Some(&entity) {
+            continue;
+        }
+
+        announced.insert((id, index), entity);
+
+        ServerMessage::PlayerConnected {
+            id,
+            index,
+            entity: ServerEntity::from_entity(entity),
+        }
+        .broadcast(&mut server);
+
+        ownership.assign(id, entity, *tick);
+    }
+
+    ownership.clean(entities, *tick);
+
+    let connected_clients = server.clients_id();
+    for (client_id, message) in ownership.drain_pending() {
+        if connected_clients.contains(&client_id) {
+            message.send(&mut server, client_id);
+        }
+    }
+}
+
+pub fn client_recv_messages(
+    mut commands: Commands,
+    tick: Option<Res<NetworkTick>>,
+    mut client: ResMut<RenetClient>,
+    mut lobby: ResMut<Lobby>,
+    entities: &Entities,
+    mut server_entities: ResMut<ServerEntities>,
+    mut received: ResMut<ReceivedServerMessages>,
+    mut player_connected: EventWriter<PlayerConnected>,
+    mut player_disconnected: EventWriter<PlayerDisconnected>,
+    mut ownership_assigned: EventWriter<OwnershipAssigned>,
+    mut ownership_revoked: EventWriter<OwnershipRevoked>,
+    mut adopted: ResMut<AdoptedSpawns>,
+    predicted_spawns: Query<(Entity, &PredictionKey), Without<ServerEntity>>,
+    predicted: Query<(), With<Predicted>>,
+    ownership_predicted: Query<(), With<OwnershipPredicted>>,
+) {
+    while let Some(message) = client.receive_message(ServerChannel::Message.id()) {
+        let message: ServerMessage = match bincode::deserialize(&message) {
+            Ok(message) => message,
+            Err(err) => {
+                error!("could not deserialize server message: {}", err);
+                continue;
+            }
+        };
+
+        match message {
+            ServerMessage::SetPlayer { id } => {
+                info!("assigned player id {}", id);
+                commands.insert_resource(LocalPlayer(id));
+            }
+            ServerMessage::SetRngSeed { seed } => {
+                commands.insert_resource(NetworkRng::new(seed));
+                // Don't roll back to the seed we had before the server's.
+                commands.insert_resource(ResourceSnapshotBuffer::<NetworkRng>::new());
+            }
+            ServerMessage::AssignOwnership {
+                entity,
+                tick: owned_tick,
+            } => {
+                let entity = server_entities.spawn_or_get(&mut commands, entity);
+                commands.entity(entity).insert(Owned);
+                // Leave it alone if the game already predicts it.
+                if !predicted.contains(entity) {
+                    commands
+                        .entity(entity)
+                        .insert((Predicted, OwnershipPredicted));
+                }
+                ownership_assigned.send(OwnershipAssigned {
+                    entity,
+                    tick: owned_tick,
+                });
+
+                // We are most likely ahead of the server, so replay from when
+                // we were supposed to start predicting.
+                if matches!(tick, Some(ref tick) if owned_tick <= **tick) {
+                    commands.add(RequestRewind(owned_tick));
+                }
+            }
+            ServerMessage::RevokeOwnership {
+                entity,
+                tick: revoked_tick,
+            } => {
+                if let Some(entity) = server_entities.get(entities, entity) {
+                    commands.entity(entity).remove::<Owned>();
+                    if ownership_predicted.contains(entity) {
+                        commands
+                            .entity(entity)
+                            .remove::<(Predicted, OwnershipPredicted)>();
+                    }
+                    ownership_revoked.send(OwnershipRevoked {
+                        entity,
+                        tick: revoked_tick,
+                    });
+
+                    if matches!(tick, Some(ref tick) if revoked_tick <= **tick) {
+                        commands.add(RequestRewind(revoked_tick));
+                    }
+                }
+            }
+            ServerMessage::ConfirmSpawn { entity, key } => {
+                let predicted = predicted_spawns
+                    .iter()
+                    .find(|(_, predicted_key)| **predicted_key == key)
+                    .map(|(predicted, _)| predicted);
+
+                adopt_predicted_spawn(
+                    &mut commands,
+                    entities,
+                    &mut server_entities,
+                    &mut adopted,
+                    predicted,
+                    entity,
+                    key,
+                );
+            }
+            ServerMessage::PlayerConnected { id, index, entity } => {
+                let entity = server_entities.spawn_or_get(&mut commands, entity);
+                lobby.insert_player(id, index, entity);
+                player_connected.send(PlayerConnected { id, index, entity });
+            }
+            ServerMessage::PlayerDisconnected { id, index } => {
+                lobby.remove_player(id, index);
+                player_disconnected.send(PlayerDisconnected { id, index });
+            }
+            ServerMessage::Custom { id, tick, data } => {
+                if !received.push(id, tick, data) {
+                    warn!("server sent unregistered message {}", id);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::pending::hold_between_ticks;
+
+    #[test]
+    pub fn messages_between_ticks() {
+        let messages = hold_between_ticks(
+            (0..4)
+                .map(|message| SendServerMessage::new(MessageTarget::All, message))
+                .collect(),
+        )
+        .into_iter()
+        .map(|(_, message)| message.message)
+        .collect::<Vec<_>>();
+        assert_eq!(messages, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    pub fn drain_ready() {
+        let mut received = ReceivedServerMessages::new();
+        received.register(1);
+
+        assert!(received.push(1, None, vec![0]));
+        assert!(received.push(1, Some(NetworkTick::new(10)), vec![1]));
+        assert!(received.push(1, Some(NetworkTick::new(20)), vec![2]));
+        assert!(!received.push(2, None, vec![3]));
+
+        let ready = received.drain_ready(1, Some(NetworkTick::new(15)));
+        assert_eq!(
+            ready.into_iter().map(|(_, data)| data).collect::<Vec<_>>(),
+            vec![vec![0], vec![1]]
+        );
+
+        // Held until we reach its tick.
+        assert!(received
+            .drain_ready(1, Some(NetworkTick::new(19)))
+            .is_empty());
+        assert_eq!(received.drain_ready(1, Some(NetworkTick::new(20))).len(), 1);
+    }
+}
@@ -20,15 +20,40 @@ use crate::prelude::*;
 
 pub mod ack;
 pub mod client;
+pub mod command;
 pub mod demands;
+pub mod event;
+pub mod extrapolation;
 pub mod input;
 pub mod interest;
+pub mod interpolation;
+pub mod lag_compensation;
+pub mod message;
+pub mod ownership;
+pub mod pending;
 pub mod resim;
 pub mod server;
+pub mod smoothing;
+pub mod spawn;
 pub mod update;
+pub mod validation;
 
 pub use client::*;
+pub use command::{ClientCommand, ClientCommandAppExt, ClientCommandPlugin, SendCommand};
+pub use event::{NetworkEvent, NetworkEventAppExt, NetworkEventPlugin, SendNetworkEvent};
+pub use extrapolation::{DeadReckoningSettings, Extrapolated};
+pub use input::{RelayInputs, SubTick, SubTickInputs};
+pub use interpolation::{Interpolate, InterpolateAppExt, InterpolatePlugin, Interpolated};
+pub use lag_compensation::{LagCompensated, LagCompensation, LagCompensationSettings};
+pub use message::{
+    MessageTarget, OwnershipAssigned, OwnershipRevoked, PlayerConnected, PlayerDisconnected,
+    SendServerMessage, ServerMessageAppExt, ServerMessageEvent, ServerMessagePlugin,
+};
+pub use ownership::Ownership;
+pub use resim::{Predicted, RollbackResourceAppExt, RollbackResourcePlugin};
 pub use server::*;
+pub use smoothing::{CorrectionSettings, CorrectionVisual, SmoothCorrection};
+pub use spawn::{PredictedSpawnSettings, PredictionKey};
 pub use update::{ComponentsUpdate, EntityUpdate};
 
 /// Private key for signing connect tokens for clients.
@@ -74,12 +99,14 @@ impl ServerChannel {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ClientChannel {
     Input,
+    Command,
 }
 
 impl ClientChannel {
     pub fn id(&self) -> u8 {
         match *self {
             ClientChannel::Input => 0,
+            ClientChannel::Command => 1,
         }
     }
 
@@ -89,11 +116,15 @@ impl ClientChannel {
                 channel_id: self.id(),
                 ..Default::default()
             }),
+            ClientChannel::Command => ChannelConfig::Reliable(ReliableChannelConfig {
+                channel_id: self.id(),
+                ..Default::default()
+            }),
         }
     }
 
     pub fn configs() -> Vec<ChannelConfig> {
-        let channels = vec![ClientChannel::Input];
+        let channels = vec![ClientChannel::Input, ClientChannel::Command];
         channels.iter().map(|channel| channel.config()).collect()
     }
 }
@@ -105,19 +136,59 @@ impl ClientChannel {
 #[derive(Debug, Deserialize, Component, Reflect)]
 pub struct Owned;
 
+/// `Predicted` was added because we own this entity, so it goes away with `Owned`.
+///
+/// Entities the game marks `Predicted` itself keep it when ownership is revoked.
+#[derive(Default, Debug, Clone, Copy, Component)]
+pub struct OwnershipPredicted;
+
 /// Reliable protocol from the server to the clients for communicating the
 /// overall gamestate and assigning what the clients should predict.
-#[derive(Debug, Serialize, Deserialize, Component)]
+///
+/// Ownership changes carry the tick the client should start/stop predicting from.
+///
+/// `Custom` carries game specific messages registered with `add_server_message`.
+#[derive(Debug, Clone, Serialize, Deserialize, Component)]
 pub enum ServerMessage {
-    SetPlayer { id: ClientId },
-    AssignOwnership { entity: ServerEntity },
-    PlayerConnected { id: ClientId, entity: ServerEntity },
-    PlayerDisconnected { id: ClientId },
+    SetPlayer {
+        id: ClientId,
+    },
+    /// Seed for `NetworkRng`, so clients roll the same numbers we do.
+    SetRngSeed {
+        seed: u64,
+    },
+    AssignOwnership {
+        entity: ServerEntity,
+        tick: NetworkTick,
+    },
+    RevokeOwnership {
+        entity: ServerEntity,
+        tick: NetworkTick,
+    },
+    PlayerConnected {
+        id: ClientId,
+        index: LocalIndex,
+        entity: ServerEntity,
+    },
+    PlayerDisconnected {
+        id: ClientId,
+        index: LocalIndex,
+    },
+    /// The server spawned an entity the client predicted, see `PredictionKey`.
+    ConfirmSpawn {
+        entity: ServerEntity,
+        key: PredictionKey,
+    },
+    Custom {
+        id: u64,
+        tick: Option<NetworkTick>,
+        data: Vec<u8>,
+    },
 }
 
 impl ServerMessage {
     pub fn protocol_id() -> u64 {
-        1
+        6
     }
 }
 
@@ -151,6 +222,10 @@ impl ServerEntity {
     pub fn from_entity(entity: Entity) -> Self {
         Self(entity.id(), entity.generation())
     }
+
+    pub fn to_bits(&self) -> u64 {
+        (self.1 as u64) << 32 | self.0 as u64
+    }
 }
 
 impl From<Entity> for ServerEntity {
@@ -175,9 +250,10 @@ pub fn localhost_ip() -> &'static str {
 /// Protocol identifier so we have more obvious breakage when we change the protocol.
 pub fn protocol_id() -> u64 {
     let concat = format!(
-        "server:{};entity:{};",
+        "server:{};entity:{};command:{};",
         ServerMessage::protocol_id().to_string(),
         EntityUpdate::protocol_id().to_string(),
+        command::CommandMessage::protocol_id().to_string(),
     );
     let mut s = std::collections::hash_map::DefaultHasher::new();
     concat.hash(&mut s);
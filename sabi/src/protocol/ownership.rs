@@ -0,0 +1,238 @@
+use bevy::{
+    ecs::entity::Entities,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::prelude::*;
+
+/// Which client controls an entity and from which tick onwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub client_id: ClientId,
+    pub since: NetworkTick,
+}
+
+/// Server-side record of which clients control which entities.
+///
+/// Changes are queued up as `ServerMessage`s so clients know when to start
+/// and stop predicting an entity. Inputs from a client are routed to the
+/// entities it owns starting at the tick ownership was assigned.
+///
+/// A client can own any number of entities, e.g. a squad of units or a vehicle
+/// along with its character, `Lobby` routing picks which of them get input.
+#[derive(Default, Debug, Clone, Resource)]
+pub struct Ownership {
+    owners: HashMap<Entity, Owner>,
+    pending: Vec<(ClientId, ServerMessage)>,
+}
+
+impl Ownership {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give `client_id` control over `entity` starting at `tick`.
+    ///
+    /// The previous owner, if any, loses control immediately.
+    pub fn assign(&mut self, client_id: ClientId, entity: Entity, tick: NetworkTick) {
+        let server_entity = ServerEntity::from_entity(entity);
+
+        // Already theirs, keep the tick they got it on so their input isn't dropped.
+        if let Some(previous) = self.owners.get(&entity) {
+            if previous.client_id == client_id {
+                return;
+            }
+
+            self.pending.push((
+                previous.client_id,
+                ServerMessage::RevokeOwnership {
+                    entity: server_entity,
+                    tick,
+                },
+            ));
+        }
+
+        self.owners.insert(
+            entity,
+            Owner {
+                client_id,
+                since: tick,
+            },
+        );
+
+        self.pending.push((
+            client_id,
+            ServerMessage::AssignOwnership {
+                entity: server_entity,
+                tick,
+            },
+        ));
+    }
+
+    /// Take control of `entity` away from whoever owns it, starting at `tick`.
+    pub fn revoke(&mut self, entity: Entity, tick: NetworkTick) {
+        if let Some(previous) = self.owners.remove(&entity) {
+            self.pending.push((
+                previous.client_id,
+                ServerMessage::RevokeOwnership {
+                    entity: ServerEntity::from_entity(entity),
+                    tick,
+                },
+            ));
+        }
+    }
+
+    /// Forget everything owned by a client, usually because they disconnected.
+    pub fn disconnect(&mut self, client_id: ClientId) {
+        self.owners.retain(|_, owner| owner.client_id != client_id);
+        self.pending.retain(|(id, _)| *id != client_id);
+    }
+
+    pub fn get(&self, entity: Entity) -> Option<&Owner> {
+        self.owners.get(&entity)
+    }
+
+    /// Client that controls `entity` on `tick`.
+    pub fn owner(&self, entity: Entity, tick: NetworkTick) -> Option<ClientId> {
+        self.owners
+            .get(&entity)
+            .filter(|owner| owner.since <= tick)
+            .map(|owner| owner.client_id)
+    }
+
+    /// Entities that `client_id` controls on `tick`.
+    pub fn owned(
+        &self,
+        client_id: ClientId,
+        tick: NetworkTick,
+    ) -> impl Iterator<Item = Entity> + '_ {
+        self.owners
+            .iter()
+            .filter(move |(_, owner)| owner.client_id == client_id && owner.since <= tick)
+            .map(|(entity, _)| *entity)
+    }
+
+    /// Clients that own anything on `tick`.
+    pub fn clients(&self, tick: NetworkTick) -> HashSet<ClientId> {
+        self.owners
+            .values()
+            .filter(|owner| owner.since <= tick)
+            .map(|owner| owner.client_id)
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Entity, &Owner)> {
+        self.owners.iter()
+    }
+
+    /// Messages that still need to be sent to clients.
+    pub fn drain_pending(&mut self) -> impl Iterator<Item = (ClientId, ServerMessage)> + '_ {
+        self.pending.drain(..)
+    }
+
+    /// Remove any entities that have been despawned, revoking them from their owners.
+    pub fn clean(&mut self, entities: &Entities, tick: NetworkTick) {
+        let despawned = self
+            .owners
+            .keys()
+            .filter(|entity| !entities.contains(**entity))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for entity in despawned {
+            self.revoke(entity, tick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn assign_and_revoke() {
+        let mut ownership = Ownership::new();
+        let entity = Entity::from_raw(1);
+
+        ownership.assign(1, entity, NetworkTick::new(10));
+        assert_eq!(ownership.owner(entity, NetworkTick::new(9)), None);
+        assert_eq!(ownership.owner(entity, NetworkTick::new(10)), Some(1));
+
+        // Handing it over should revoke from the previous owner.
+        ownership.assign(2, entity, NetworkTick::new(20));
+        assert_eq!(ownership.owned(1, NetworkTick::new(20)).count(), 0);
+        assert_eq!(ownership.owner(entity, NetworkTick::new(20)), Some(2));
+
+        ownership.revoke(entity, NetworkTick::new(30));
+        assert_eq!(ownership.owner(entity, NetworkTick::new(30)), None);
+
+        let pending = ownership
+            .drain_pending()
+            .map(|(client_id, message)| match message {
+                ServerMessage::AssignOwnership { .. } => (client_id, true),
+                ServerMessage::RevokeOwnership { .. } => (client_id, false),
+                _ => panic!("unexpected message"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(pending, vec![(1, true), (1, false), (2, true), (2, false)]);
+
+        // Assigning to the current owner again doesn't move when they got it.
+        ownership.assign(1, entity, NetworkTick::new(40));
+        ownership.drain_pending().count();
+        ownership.assign(1, entity, NetworkTick::new(50));
+        assert_eq!(ownership.owner(entity, NetworkTick::new(40)), Some(1));
+        assert_eq!(ownership.drain_pending().count(), 0);
+    }
+
+    #[test]
+    pub fn clean_despawned() {
+        let mut world = World::new();
+        let entity = world.spawn_empty().id();
+
+        let mut ownership = Ownership::new();
+        ownership.assign(1, entity, NetworkTick::new(10));
+        ownership.drain_pending().count();
+
+        world.despawn(entity);
+        ownership.clean(world.entities(), NetworkTick::new(20));
+        assert_eq!(ownership.get(entity), None);
+
+        let pending = ownership.drain_pending().collect::<Vec<_>>();
+        assert!(matches!(
+            pending.as_slice(),
+            [(1, ServerMessage::RevokeOwnership { tick, .. })] if *tick == NetworkTick::new(20)
+        ));
+    }
+    #[test]
+    pub fn input_follows_ownership() {
+        let player = Entity::from_raw(1);
+        let unit = Entity::from_raw(2);
+
+        let mut lobby = Lobby::new();
+        lobby.insert_player(1, 0, player);
+
+        let mut ownership = Ownership::new();
+        ownership.assign(1, player, NetworkTick::new(10));
+        // Owning something is enough to get input, no lobby entry needed.
+        ownership.assign(2, unit, NetworkTick::new(10));
+
+        let tick = NetworkTick::new(10);
+        let targets = |ownership: &Ownership, client_id| {
+            lobby.input_targets(client_id, ownership.owned(client_id, tick))
+        };
+        assert_eq!(ownership.clients(tick), HashSet::from_iter([1, 2]));
+        assert_eq!(targets(&ownership, 1), HashSet::from_iter([player]));
+        assert_eq!(targets(&ownership, 2), HashSet::from_iter([unit]));
+
+        // Handing our player to someone else stops our input driving it.
+        ownership.assign(2, player, tick);
+        assert!(targets(&ownership, 1).is_empty());
+        assert_eq!(targets(&ownership, 2), HashSet::from_iter([player, unit]));
+
+        ownership.revoke(player, tick);
+        ownership.revoke(unit, tick);
+        assert!(ownership.clients(tick).is_empty());
+        assert!(targets(&ownership, 2).is_empty());
+    }
+}
@@ -0,0 +1,158 @@
+use bevy::{
+    ecs::event::{Event, ManualEventReader},
+    prelude::*,
+};
+use iyes_loopless::prelude::IntoConditionalSystem;
+
+use crate::{prelude::*, stage::NetworkSimulationAppExt};
+
+/// Events sent since the last tick, stamped with the tick they were sent on.
+///
+/// Events only live for a couple of frames and we don't tick every frame, so
+/// commands, server messages and network events are held here until the next
+/// tick sends them. They are read with a cursor, so other readers still see them.
+#[derive(Resource)]
+pub struct PendingEvents<E: Event> {
+    reader: ManualEventReader<E>,
+    events: Vec<(NetworkTick, E)>,
+}
+
+impl<E: Event> Default for PendingEvents<E> {
+    fn default() -> Self {
+        Self {
+            reader: ManualEventReader::default(),
+            events: Vec::new(),
+        }
+    }
+}
+
+impl<E: Event> PendingEvents<E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, tick: NetworkTick, event: E) {
+        self.events.push((tick, event));
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item = (NetworkTick, E)> + '_ {
+        self.events.drain(..)
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+/// Hold onto events sent this frame until the next tick, runs every frame.
+pub fn hold_pending_events<E>(
+    tick: Res<NetworkTick>,
+    sent: Res<Events<E>>,
+    mut pending: ResMut<PendingEvents<E>>,
+) where
+    E: Event + Clone,
+{
+    let PendingEvents { reader, events } = &mut *pending;
+    for event in reader.iter(&sent) {
+        events.push((*tick, event.clone()));
+    }
+}
+
+pub trait PendingEventsAppExt {
+    /// Hold `E` in [`PendingEvents`] until the next tick, systems draining it
+    /// should run after `"hold_pending_events"` in the meta stage.
+    fn add_pending_events<E>(&mut self) -> &mut Self
+    where
+        E: Event + Clone;
+}
+
+impl PendingEventsAppExt for App {
+    fn add_pending_events<E>(&mut self) -> &mut Self
+    where
+        E: Event + Clone,
+    {
+        self.add_event::<E>();
+        self.insert_resource(PendingEvents::<E>::new());
+        self.add_system_to_stage(
+            CoreStage::PostUpdate,
+            hold_pending_events::<E>.run_if_resource_exists::<NetworkTick>(),
+        );
+        // Also pick up anything sent during the tick so it goes out right away.
+        self.add_meta_network_system(
+            hold_pending_events::<E>
+                .run_if_resource_exists::<NetworkTick>()
+                .label("hold_pending_events"),
+        )
+    }
+}
+
+/// Send each of `sent` on its own frame without a tick and return what was held.
+#[cfg(test)]
+pub(crate) fn hold_between_ticks<E>(sent: Vec<E>) -> Vec<(NetworkTick, E)>
+where
+    E: Event + Clone,
+{
+    let mut world = World::new();
+    world.insert_resource(NetworkTick::new(10));
+    world.insert_resource(Events::<E>::default());
+    world.insert_resource(PendingEvents::<E>::new());
+
+    let mut first = SystemStage::single(Events::<E>::update_system);
+    let mut post_update = SystemStage::single(hold_pending_events::<E>);
+
+    // Nothing else should lose the events because we held onto them.
+    let mut other = ManualEventReader::<E>::default();
+    let mut seen = 0;
+    for event in sent {
+        first.run(&mut world);
+        world.resource_mut::<Events<E>>().send(event);
+        post_update.run(&mut world);
+        seen += other.iter(world.resource::<Events<E>>()).count();
+    }
+    // The events would be cleared by now.
+    first.run(&mut world);
+    first.run(&mut world);
+
+    let held = world
+        .resource_mut::<PendingEvents<E>>()
+        .drain()
+        .collect::<Vec<_>>();
+    assert_eq!(held.len(), seen);
+    held
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn held_once() {
+        let mut world = World::new();
+        world.insert_resource(NetworkTick::new(3));
+        world.insert_resource(Events::<u32>::default());
+        world.insert_resource(PendingEvents::<u32>::new());
+
+        // Post update and the meta stage share the cursor, so nothing is held twice.
+        let mut post_update = SystemStage::single(hold_pending_events::<u32>);
+        let mut meta = SystemStage::single(hold_pending_events::<u32>);
+
+        world.resource_mut::<Events<u32>>().send(1);
+        post_update.run(&mut world);
+        world.resource_mut::<Events<u32>>().send(2);
+        meta.run(&mut world);
+        post_update.run(&mut world);
+
+        let held = world
+            .resource_mut::<PendingEvents<u32>>()
+            .drain()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            held,
+            vec![(NetworkTick::new(3), 1), (NetworkTick::new(3), 2)]
+        );
+    }
+}
@@ -1,23 +1,46 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    marker::PhantomData,
+};
 
 use bevy::{ecs::entity::Entities, prelude::*};
+use iyes_loopless::prelude::IntoConditionalSystem;
 
-use super::{NetworkTick, Replicate};
+use crate::{
+    prelude::*,
+    stage::{NetworkSimulationAppExt, RequestRewind},
+};
+
+use super::{interpolation::InterpolationBuffer, update::UpdateMessages, NetworkTick, Replicate};
 
 pub const SNAPSHOT_RETAIN_BUFFER: i64 = 64;
 
-#[derive(Deref, DerefMut, Debug)]
-pub struct ComponentSnapshot<C>(BTreeMap<Entity, C>);
+/// Entities the client predicts, only these get snapshotted and rewound.
+///
+/// Added and removed along with `Owned` when the server assigns ownership,
+/// insert it yourself to predict entities we don't own.
+/// Everything else just takes the server state as it comes in, so game systems
+/// that move things around should filter on this to avoid moving them twice in a resim.
+#[derive(Default, Debug, Clone, Copy, Component, Reflect)]
+pub struct Predicted;
 
-impl<C> Default for ComponentSnapshot<C> {
-    fn default() -> Self {
-        Self(Default::default())
-    }
+/// Components that changed on a tick, along with what they were before.
+///
+/// `None` means the entity didn't have the component before this tick.
+#[derive(Debug)]
+pub struct SnapshotDelta<C> {
+    pub tick: NetworkTick,
+    pub previous: BTreeMap<Entity, Option<C>>,
 }
 
+/// History of a component on predicted entities.
+///
+/// Only the latest state is stored in full, each tick just keeps what changed
+/// so it can be undone back to any tick still in the ring.
 #[derive(Debug, Resource)]
 pub struct SnapshotBuffer<C> {
-    snapshots: BTreeMap<NetworkTick, ComponentSnapshot<C>>,
+    current: BTreeMap<Entity, C>,
+    deltas: VecDeque<SnapshotDelta<C>>,
 }
 
 impl<C> Default for SnapshotBuffer<C> {
@@ -29,58 +52,863 @@ impl<C> Default for SnapshotBuffer<C> {
 impl<C> SnapshotBuffer<C> {
     pub fn new() -> Self {
         Self {
-            snapshots: Default::default(),
+            current: Default::default(),
+            deltas: VecDeque::with_capacity(SNAPSHOT_RETAIN_BUFFER as usize),
         }
     }
 
-    pub fn push(&mut self, tick: NetworkTick, snapshot: ComponentSnapshot<C>) {
-        self.snapshots.insert(tick, snapshot);
+    /// Start recording `tick`, dropping the oldest tick if the ring is full.
+    pub fn begin(&mut self, tick: NetworkTick) {
+        if matches!(self.deltas.back(), Some(delta) if delta.tick == tick) {
+            return;
+        }
+
+        self.deltas.push_back(SnapshotDelta {
+            tick,
+            previous: Default::default(),
+        });
+
+        while self.deltas.len() > SNAPSHOT_RETAIN_BUFFER as usize {
+            self.deltas.pop_front();
+        }
+    }
+
+    /// Whether we can get back to `tick`.
+    pub fn contains(&self, tick: &NetworkTick) -> bool {
+        match (self.deltas.front(), self.deltas.back()) {
+            (Some(oldest), Some(newest)) => oldest.tick <= *tick && *tick <= newest.tick,
+            _ => false,
+        }
+    }
+
+    pub fn is_tracked(&self, entity: &Entity) -> bool {
+        self.current.contains_key(entity)
+    }
+
+    pub fn tracked(&self) -> impl Iterator<Item = &Entity> {
+        self.current.keys()
+    }
+
+    /// Components held, the current state plus every change we can undo.
+    pub fn stored(&self) -> usize {
+        self.current.len()
+            + self
+                .deltas
+                .iter()
+                .map(|delta| delta.previous.len())
+                .sum::<usize>()
+    }
+
+    /// The component on `entity` at the end of `tick`.
+    pub fn get(&self, tick: &NetworkTick, entity: &Entity) -> Option<&C> {
+        if !self.contains(tick) {
+            return None;
+        }
+
+        // Oldest change after the tick is what it was at the tick.
+        for delta in self.deltas.iter().filter(|delta| delta.tick > *tick) {
+            if let Some(previous) = delta.previous.get(entity) {
+                return previous.as_ref();
+            }
+        }
+
+        self.current.get(entity)
+    }
+
+    /// `entity` no longer has the component, or isn't predicted anymore.
+    pub fn remove(&mut self, entity: &Entity) {
+        if let Some(previous) = self.current.remove(entity) {
+            self.record(*entity, Some(previous));
+        }
+    }
+
+    /// Undo everything after `tick`, returning what changed entities were at the tick.
+    pub fn rewind(&mut self, tick: &NetworkTick) -> Option<BTreeMap<Entity, Option<C>>>
+    where
+        C: Clone,
+    {
+        if !self.contains(tick) {
+            return None;
+        }
+
+        let mut restored = BTreeMap::new();
+        while matches!(self.deltas.back(), Some(delta) if delta.tick > *tick) {
+            if let Some(delta) = self.deltas.pop_back() {
+                restored.extend(delta.previous);
+            }
+        }
+
+        for (entity, previous) in restored.iter() {
+            match previous {
+                Some(previous) => self.current.insert(*entity, previous.clone()),
+                None => self.current.remove(entity),
+            };
+        }
+
+        Some(restored)
+    }
+
+    /// Point history of respawned entities at their new entity.
+    pub fn remap(&mut self, respawned: &BTreeMap<Entity, Entity>) {
+        for (old, new) in respawned.iter() {
+            if let Some(component) = self.current.remove(old) {
+                self.current.insert(*new, component);
+            }
+
+            for delta in self.deltas.iter_mut() {
+                if let Some(previous) = delta.previous.remove(old) {
+                    delta.previous.insert(*new, previous);
+                }
+            }
+        }
+    }
+
+    /// Keep the first value recorded for the tick, that's what it was before the tick.
+    fn record(&mut self, entity: Entity, previous: Option<C>) {
+        if let Some(delta) = self.deltas.back_mut() {
+            delta.previous.entry(entity).or_insert(previous);
+        }
+    }
+}
+
+impl<C> SnapshotBuffer<C>
+where
+    C: Replicate + Clone,
+{
+    /// Latest state of `entity`, only recorded if it actually changed.
+    pub fn update(&mut self, entity: Entity, component: &C) {
+        if let Some(current) = self.current.get(&entity) {
+            if current.clone().into_def() == component.clone().into_def() {
+                return;
+            }
+        }
+
+        let previous = self.current.insert(entity, component.clone());
+        self.record(entity, previous);
+    }
+}
+
+/// History of a simulation resource, `None` when it didn't exist yet.
+#[derive(Debug, Resource)]
+pub struct ResourceSnapshotBuffer<R> {
+    snapshots: VecDeque<(NetworkTick, Option<R>)>,
+}
+
+impl<R> Default for ResourceSnapshotBuffer<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R> ResourceSnapshotBuffer<R> {
+    pub fn new() -> Self {
+        Self {
+            snapshots: VecDeque::with_capacity(SNAPSHOT_RETAIN_BUFFER as usize),
+        }
+    }
+
+    pub fn push(&mut self, tick: NetworkTick, resource: Option<R>) {
+        // Replaying a tick replaces whatever we had for it.
+        while matches!(self.snapshots.back(), Some((last, _)) if *last >= tick) {
+            self.snapshots.pop_back();
+        }
+
+        self.snapshots.push_back((tick, resource));
+
+        while self.snapshots.len() > SNAPSHOT_RETAIN_BUFFER as usize {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn get(&self, tick: &NetworkTick) -> Option<&Option<R>> {
+        self.snapshots
+            .iter()
+            .rev()
+            .find(|(snapshot_tick, _)| snapshot_tick == tick)
+            .map(|(_, resource)| resource)
+    }
+}
+
+/// Restores `R` when rewinding, for simulation state kept in resources
+/// like spawn counters or wave timers.
+pub struct RollbackResourcePlugin<R>(PhantomData<R>);
+
+impl<R> Default for RollbackResourcePlugin<R> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<R> Plugin for RollbackResourcePlugin<R>
+where
+    R: 'static + Send + Sync + Resource + Clone,
+{
+    fn build(&self, app: &mut App) {
+        if !app.world.contains_resource::<crate::Client>() {
+            return;
+        }
+
+        app.insert_resource(ResourceSnapshotBuffer::<R>::new());
+        app.add_meta_network_system(
+            store_resource_snapshot::<R>.run_if_resource_exists::<NetworkTick>(),
+        );
+        // Stored again while replaying, so they match the corrected timeline.
+        app.add_input_history_network_system(
+            store_resource_snapshot::<R>.run_if_resource_exists::<NetworkTick>(),
+        );
+        app.add_rewind_network_system(rewind_resource::<R>.run_if_resource_exists::<NetworkTick>());
+    }
+}
+
+pub trait RollbackResourceAppExt {
+    /// Snapshot `R` every tick and restore it when rewinding.
+    fn add_rollback_resource<R>(&mut self) -> &mut Self
+    where
+        R: 'static + Send + Sync + Resource + Clone;
+}
+
+impl RollbackResourceAppExt for App {
+    fn add_rollback_resource<R>(&mut self) -> &mut Self
+    where
+        R: 'static + Send + Sync + Resource + Clone,
+    {
+        self.add_plugin(RollbackResourcePlugin::<R>::default())
+    }
+}
+
+/// Which predicted entities were alive at each tick, so a rewind can undo
+/// spawns and despawns that happened after it.
+#[derive(Default, Debug, Clone, Resource)]
+pub struct PredictedEntities {
+    alive: BTreeMap<NetworkTick, BTreeMap<Entity, Option<ServerEntity>>>,
+    /// Entities respawned by the last rewind, old entity to new entity.
+    respawned: BTreeMap<Entity, Entity>,
+}
+
+/// Spawns and despawns needed to get predicted entities back to a tick.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct EntityRewind {
+    /// Spawned locally after the tick.
+    pub despawn: Vec<Entity>,
+    /// Despawned after the tick, along with the server entity it was if any.
+    pub respawn: Vec<(Entity, Option<ServerEntity>)>,
+}
+
+impl PredictedEntities {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, tick: NetworkTick, alive: BTreeMap<Entity, Option<ServerEntity>>) {
+        self.alive.insert(tick, alive);
 
         self.clean_old();
     }
 
     pub fn clean_old(&mut self) {
-        let newest = self.snapshots.keys().max().cloned().unwrap_or_default();
+        let newest = self.alive.keys().max().cloned().unwrap_or_default();
 
-        self.snapshots.retain(|tick, _| {
+        self.alive.retain(|tick, _| {
             (newest.tick() as i64) - (tick.tick() as i64) < SNAPSHOT_RETAIN_BUFFER
         });
     }
+
+    pub fn is_alive(&self, tick: &NetworkTick, entity: &Entity) -> bool {
+        self.alive
+            .get(tick)
+            .map(|alive| alive.contains_key(entity))
+            .unwrap_or(false)
+    }
+
+    /// Drop an entity from history so rewinding never brings it back.
+    pub fn forget(&mut self, entity: &Entity) {
+        for alive in self.alive.values_mut() {
+            alive.remove(entity);
+        }
+    }
+
+    pub fn respawned(&self) -> &BTreeMap<Entity, Entity> {
+        &self.respawned
+    }
+
+    /// What needs to be spawned and despawned to get back to `tick`.
+    ///
+    /// `current` is every predicted entity right now. Server entities that only
+    /// started being predicted after the tick are left alone.
+    pub fn rewind(
+        &self,
+        tick: &NetworkTick,
+        current: &BTreeMap<Entity, Option<ServerEntity>>,
+        exists: impl Fn(&Entity) -> bool,
+    ) -> Option<EntityRewind> {
+        let alive = self.alive.get(tick)?;
+
+        let despawn = current
+            .iter()
+            .filter(|(entity, server_entity)| {
+                server_entity.is_none() && !alive.contains_key(entity)
+            })
+            .map(|(entity, _)| *entity)
+            .collect();
+
+        let respawn = alive
+            .iter()
+            .filter(|(entity, _)| !current.contains_key(entity) && !exists(entity))
+            .map(|(entity, server_entity)| (*entity, *server_entity))
+            .collect();
+
+        Some(EntityRewind { despawn, respawn })
+    }
+
+    /// Point everything we know about respawned entities at their new entity.
+    pub fn remap(&mut self, respawned: BTreeMap<Entity, Entity>) {
+        for alive in self.alive.values_mut() {
+            for (old, new) in respawned.iter() {
+                if let Some(server_entity) = alive.remove(old) {
+                    alive.insert(*new, server_entity);
+                }
+            }
+        }
+
+        self.respawned = respawned;
+    }
+}
+
+/// How close our prediction of `C` has to be to the server's to skip rewinding,
+/// `Exact` unless `SabiPlugin` picked something better for a built-in type.
+#[derive(Resource)]
+pub enum PredictionTolerance<C> {
+    /// Predicted state has to serialize to the same thing as the server's.
+    Exact,
+    /// Predicted state is close enough to the server's.
+    Within(fn(&C, &C) -> bool),
+    /// Never rewind for `C`, for state derived from other components like
+    /// `GlobalTransform` that is recomputed outside the tick anyway.
+    Ignore,
+}
+
+impl<C> Default for PredictionTolerance<C> {
+    fn default() -> Self {
+        Self::Exact
+    }
+}
+
+impl<C> PredictionTolerance<C>
+where
+    C: Replicate + Clone,
+{
+    pub fn matches(&self, predicted: &C, server: &C) -> bool {
+        match self {
+            Self::Exact => predicted.clone().into_def() == server.clone().into_def(),
+            Self::Within(within) => within(predicted, server),
+            Self::Ignore => true,
+        }
+    }
+}
+
+/// How many times we have mispredicted each component type.
+#[derive(Default, Debug, Clone, Resource)]
+pub struct Mispredictions {
+    counts: BTreeMap<ReplicateId, u64>,
+}
+
+impl Mispredictions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, replicate_id: ReplicateId) {
+        *self.counts.entry(replicate_id).or_default() += 1;
+    }
+
+    pub fn get(&self, replicate_id: &ReplicateId) -> u64 {
+        self.counts.get(replicate_id).cloned().unwrap_or_default()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ReplicateId, &u64)> {
+        self.counts.iter()
+    }
+}
+
+/// Compare updates we just got from the server with what we predicted,
+/// only rewinding if we got something wrong.
+pub fn client_check_prediction<C>(
+    mut commands: Commands,
+    entities: &Entities,
+    tick: Res<NetworkTick>,
+    tolerance: Res<PredictionTolerance<C>>,
+    snapshots: Res<SnapshotBuffer<C>>,
+    server_updates: Res<UpdateMessages>,
+    server_entities: Res<ServerEntities>,
+    mut mispredictions: ResMut<Mispredictions>,
+    mut interpolated: Query<&mut InterpolationBuffer<C>>,
+    predicted: Query<(), With<Predicted>>,
+) where
+    C: 'static + Send + Sync + Component + Replicate + Clone,
+{
+    let mut rewind: Option<NetworkTick> = None;
+
+    // Updates for ticks we haven't simulated yet stay unchecked until we get there.
+    for update_tick in server_updates.unchecked(&*tick) {
+        let update = match server_updates.get(update_tick) {
+            Some(update) => update,
+            None => continue,
+        };
+
+        for (server_entity, components) in update.entity_update.iter() {
+            let data = match components.get(&C::replicate_id()) {
+                Some(data) => data,
+                None => continue,
+            };
+
+            let def: <C as Replicate>::Def = match bincode::deserialize(data) {
+                Ok(def) => def,
+                Err(_) => continue,
+            };
+            let server = C::from_def(def);
+
+            let entity = server_entities.get(entities, *server_entity);
+            if let Some(entity) = entity {
+                // Not predicted, so nothing to rewind.
+                if let Ok(mut buffer) = interpolated.get_mut(entity) {
+                    buffer.push(*update_tick, server);
+                    continue;
+                }
+
+                if !predicted.contains(entity) {
+                    // Updates can arrive out of order, only take the newest one.
+                    let latest =
+                        server_updates.last_component_update(server_entity, &C::replicate_id());
+                    if latest == Some(update_tick) {
+                        commands.entity(entity).insert(server);
+                    }
+                    continue;
+                }
+            }
+
+            // Entities we haven't spawned yet get picked up by the resim.
+            let predicted = entity.and_then(|entity| snapshots.get(update_tick, &entity));
+            let matches = match (&*tolerance, predicted) {
+                (PredictionTolerance::Ignore, _) => true,
+                (_, Some(predicted)) => tolerance.matches(predicted, &server),
+                (_, None) => false,
+            };
+
+            if !matches {
+                mispredictions.record(C::replicate_id());
+                if rewind.map_or(true, |rewind| *update_tick < rewind) {
+                    rewind = Some(*update_tick);
+                }
+            }
+        }
+    }
+
+    if let Some(rewind) = rewind {
+        commands.add(RequestRewind(rewind));
+    }
 }
 
 pub fn store_snapshot<C>(
     tick: Res<NetworkTick>,
     mut snapshots: ResMut<SnapshotBuffer<C>>,
-    query: Query<(Entity, &C)>,
+    query: Query<(Entity, &C, ChangeTrackers<C>), With<Predicted>>,
 ) where
     C: 'static + Send + Sync + Component + Replicate + Clone,
 {
-    let mut snapshot = ComponentSnapshot::default();
-    for (entity, component) in query.iter() {
-        snapshot.insert(entity, component.clone());
+    snapshots.begin(*tick);
+
+    for (entity, component, tracker) in query.iter() {
+        if tracker.is_changed() || !snapshots.is_tracked(&entity) {
+            snapshots.update(entity, component);
+        }
+    }
+
+    let removed = snapshots
+        .tracked()
+        .filter(|entity| !query.contains(**entity))
+        .cloned()
+        .collect::<Vec<_>>();
+    for entity in removed {
+        snapshots.remove(&entity);
+    }
+}
+
+pub fn store_resource_snapshot<R>(
+    tick: Res<NetworkTick>,
+    resource: Option<Res<R>>,
+    mut snapshots: ResMut<ResourceSnapshotBuffer<R>>,
+) where
+    R: 'static + Send + Sync + Resource + Clone,
+{
+    snapshots.push(*tick, resource.map(|resource| resource.clone()));
+}
+
+pub fn rewind_resource<R>(
+    mut commands: Commands,
+    tick: Res<NetworkTick>,
+    resource: Option<ResMut<R>>,
+    snapshots: Res<ResourceSnapshotBuffer<R>>,
+) where
+    R: 'static + Send + Sync + Resource + Clone,
+{
+    match (snapshots.get(&*tick), resource) {
+        (Some(Some(snapshot)), Some(mut resource)) => *resource = snapshot.clone(),
+        (Some(Some(snapshot)), None) => commands.insert_resource(snapshot.clone()),
+        (Some(None), Some(_)) => commands.remove_resource::<R>(),
+        (Some(None), None) => {}
+        (None, _) => error!("no snapshot for resource: {:?}", std::any::type_name::<R>()),
     }
+}
+
+pub fn store_predicted_entities(
+    tick: Res<NetworkTick>,
+    mut predicted: ResMut<PredictedEntities>,
+    query: Query<(Entity, Option<&ServerEntity>), With<Predicted>>,
+) {
+    let alive: BTreeMap<_, _> = query
+        .iter()
+        .map(|(entity, server_entity)| (entity, server_entity.cloned()))
+        .collect();
 
-    snapshots.push(*tick, snapshot);
+    predicted.push(*tick, alive);
+}
+
+/// Despawn predicted entities spawned after the rewind tick and bring back ones despawned since.
+///
+/// Respawned entities get a new `Entity`, so snapshots are remapped before being restored.
+pub fn rewind_predicted_entities(
+    mut commands: Commands,
+    entities: &Entities,
+    tick: Res<NetworkTick>,
+    mut predicted: ResMut<PredictedEntities>,
+    mut server_entities: ResMut<ServerEntities>,
+    query: Query<(Entity, Option<&ServerEntity>), With<Predicted>>,
+) {
+    let current: BTreeMap<_, _> = query
+        .iter()
+        .map(|(entity, server_entity)| (entity, server_entity.cloned()))
+        .collect();
+
+    let rewind = match predicted.rewind(&*tick, &current, |entity| entities.contains(*entity)) {
+        Some(rewind) => rewind,
+        None => {
+            predicted.remap(BTreeMap::new());
+            return;
+        }
+    };
+
+    for entity in rewind.despawn {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    let mut respawned = BTreeMap::new();
+    for (entity, server_entity) in rewind.respawn {
+        let new_entity = match server_entity {
+            Some(server_entity) => {
+                let new_entity = commands.spawn((Predicted, server_entity)).id();
+                server_entities.insert(server_entity, new_entity);
+                new_entity
+            }
+            None => commands.spawn(Predicted).id(),
+        };
+
+        respawned.insert(entity, new_entity);
+    }
+
+    predicted.remap(respawned);
 }
 
 pub fn rewind<C>(
     mut commands: Commands,
     entities: &Entities,
     tick: Res<NetworkTick>,
-    snapshots: Res<SnapshotBuffer<C>>,
+    mut snapshots: ResMut<SnapshotBuffer<C>>,
+    predicted: Res<PredictedEntities>,
+    mut query: Query<&mut C, With<Predicted>>,
 ) where
     C: 'static + Send + Sync + Component + Replicate + Clone,
 {
-    if let Some(snapshot) = snapshots.snapshots.get(&*tick) {
-        for (entity, component) in snapshot.0.iter() {
-            if entities.contains(*entity) {
-                commands.entity(*entity).insert(component.clone());
+    snapshots.remap(predicted.respawned());
+
+    let restored = match snapshots.rewind(&*tick) {
+        Some(restored) => restored,
+        None => {
+            error!(
+                "no snapshot for component: {:?}",
+                std::any::type_name::<C>()
+            );
+            return;
+        }
+    };
+
+    // Only what changed since the tick needs touching, written in place where we can.
+    for (entity, previous) in restored {
+        match previous {
+            Some(previous) => {
+                if let Ok(mut component) = query.get_mut(entity) {
+                    *component = previous;
+                } else if entities.contains(entity) {
+                    commands.entity(entity).insert(previous);
+                }
+            }
+            None => {
+                // Added after the tick, entities that weren't predicted yet keep theirs.
+                if predicted.is_alive(&*tick, &entity) && query.contains(entity) {
+                    commands.entity(entity).remove::<C>();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        protocol::update::{
+            client_clear_checked_updates, ComponentsUpdate, EntityUpdate, UpdateMessage,
+        },
+        stage::Rewind,
+    };
+
+    #[test]
+    pub fn prediction_tolerance() {
+        let predicted = Transform::from_xyz(1.0, 0.0, 0.0);
+        let server = Transform::from_xyz(1.001, 0.0, 0.0);
+
+        let exact = PredictionTolerance::<Transform>::Exact;
+        assert!(exact.matches(&predicted, &predicted));
+        assert!(!exact.matches(&predicted, &server));
+
+        let within = PredictionTolerance::<Transform>::Within(|predicted, server| {
+            predicted.translation.distance(server.translation) < 0.01
+        });
+        assert!(within.matches(&predicted, &server));
+        assert!(!within.matches(&predicted, &Transform::default()));
+
+        let ignore = PredictionTolerance::<Transform>::Ignore;
+        assert!(ignore.matches(&predicted, &Transform::default()));
+    }
+
+    #[test]
+    pub fn snapshot_deltas() {
+        let moved = Entity::from_raw(0);
+        let added = Entity::from_raw(1);
+
+        let mut snapshots = SnapshotBuffer::new();
+        snapshots.begin(NetworkTick::new(1));
+        snapshots.update(moved, &Transform::from_xyz(0.0, 0.0, 0.0));
+        snapshots.begin(NetworkTick::new(2));
+        snapshots.update(moved, &Transform::from_xyz(0.0, 0.0, 0.0));
+        snapshots.begin(NetworkTick::new(3));
+        snapshots.update(moved, &Transform::from_xyz(3.0, 0.0, 0.0));
+        snapshots.begin(NetworkTick::new(4));
+        snapshots.update(added, &Transform::from_xyz(4.0, 0.0, 0.0));
+
+        // Nothing changed, so nothing stored.
+        assert!(snapshots.deltas[1].previous.is_empty());
+
+        let x = |tick, entity| {
+            snapshots
+                .get(&NetworkTick::new(tick), &entity)
+                .map(|transform| transform.translation.x)
+        };
+        assert_eq!(x(0, moved), None);
+        assert_eq!(x(2, moved), Some(0.0));
+        assert_eq!(x(3, moved), Some(3.0));
+        assert_eq!(x(3, added), None);
+        assert_eq!(x(4, added), Some(4.0));
+
+        let restored = snapshots.rewind(&NetworkTick::new(2)).unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(
+            restored[&moved].map(|transform| transform.translation.x),
+            Some(0.0)
+        );
+        assert!(restored[&added].is_none());
+        assert!(!snapshots.contains(&NetworkTick::new(3)));
+        assert!(!snapshots.is_tracked(&added));
+
+        for tick in 3..(3 + SNAPSHOT_RETAIN_BUFFER as u64) {
+            snapshots.begin(NetworkTick::new(tick));
+        }
+        assert!(!snapshots.contains(&NetworkTick::new(2)));
+        assert_eq!(
+            snapshots
+                .get(&NetworkTick::new(3), &moved)
+                .map(|transform| transform.translation.x),
+            Some(0.0)
+        );
+    }
+
+    #[test]
+    pub fn snapshot_delta_sizes() {
+        const ENTITIES: u32 = 100;
+        let ticks = SNAPSHOT_RETAIN_BUFFER as u64;
+
+        // Mostly idle world, a different entity is out of place each tick.
+        let mut snapshots = SnapshotBuffer::new();
+        for tick in 0..ticks {
+            snapshots.begin(NetworkTick::new(tick));
+            for entity in 0..ENTITIES {
+                let x = if entity as u64 == tick % ENTITIES as u64 {
+                    tick as f32
+                } else {
+                    0.0
+                };
+                snapshots.update(Entity::from_raw(entity), &Transform::from_xyz(x, 0.0, 0.0));
             }
         }
-    } else {
-        error!(
-            "no snapshot for component: {:?}",
-            std::any::type_name::<C>()
+
+        // A full copy of every entity on every tick is what we used to keep.
+        let full = ENTITIES as usize * ticks as usize;
+        assert!(snapshots.stored() * 10 < full);
+
+        // Rewinding only touches what changed since the tick, not every entity.
+        let restored = snapshots.rewind(&NetworkTick::new(ticks - 5)).unwrap();
+        assert!(restored.len() <= 4 * 2);
+    }
+
+    #[test]
+    pub fn resource_snapshots() {
+        let mut snapshots = ResourceSnapshotBuffer::new();
+        snapshots.push(NetworkTick::new(1), None);
+        snapshots.push(NetworkTick::new(2), Some(2u32));
+        snapshots.push(NetworkTick::new(3), Some(3u32));
+        assert_eq!(snapshots.get(&NetworkTick::new(1)), Some(&None));
+        assert_eq!(snapshots.get(&NetworkTick::new(3)), Some(&Some(3)));
+
+        // Replayed from tick 2, anything after it is stale.
+        snapshots.push(NetworkTick::new(2), Some(20));
+        assert_eq!(snapshots.get(&NetworkTick::new(2)), Some(&Some(20)));
+        assert_eq!(snapshots.get(&NetworkTick::new(3)), None);
+
+        for tick in 3..(3 + SNAPSHOT_RETAIN_BUFFER as u64) {
+            snapshots.push(NetworkTick::new(tick), None);
+        }
+        assert_eq!(snapshots.get(&NetworkTick::new(2)), None);
+    }
+
+    #[test]
+    pub fn spawn_despawn_across_rewind() {
+        let despawned = Entity::from_raw(0);
+        let spawned = Entity::from_raw(1);
+        let owned = Entity::from_raw(2);
+        let server_entity = ServerEntity::from_entity(Entity::from_raw(10));
+
+        let mut predicted = PredictedEntities::new();
+        predicted.push(NetworkTick::new(10), [(despawned, None)].into());
+        predicted.push(
+            NetworkTick::new(11),
+            [(despawned, None), (spawned, None)].into(),
+        );
+        predicted.push(NetworkTick::new(12), [(spawned, None)].into());
+
+        // Started predicting this after tick 10, but it isn't ours to despawn.
+        let current: BTreeMap<_, _> = [(spawned, None), (owned, Some(server_entity))].into();
+        let exists = |entity: &Entity| *entity != despawned;
+
+        let rewind = predicted
+            .rewind(&NetworkTick::new(10), &current, exists)
+            .unwrap();
+        assert_eq!(rewind.despawn, vec![spawned]);
+        assert_eq!(rewind.respawn, vec![(despawned, None)]);
+
+        let rewind = predicted
+            .rewind(&NetworkTick::new(11), &current, exists)
+            .unwrap();
+        assert!(rewind.despawn.is_empty());
+        assert_eq!(rewind.respawn, vec![(despawned, None)]);
+
+        assert!(predicted
+            .rewind(&NetworkTick::new(9), &current, exists)
+            .is_none());
+
+        let respawned = Entity::from_raw(3);
+        let mut snapshots = SnapshotBuffer::new();
+        snapshots.begin(NetworkTick::new(10));
+        snapshots.update(despawned, &Transform::from_xyz(1.0, 0.0, 0.0));
+        snapshots.begin(NetworkTick::new(11));
+        snapshots.begin(NetworkTick::new(12));
+        snapshots.remove(&despawned);
+
+        predicted.remap([(despawned, respawned)].into());
+        snapshots.remap(predicted.respawned());
+        assert!(predicted.is_alive(&NetworkTick::new(11), &respawned));
+        assert!(!predicted.is_alive(&NetworkTick::new(11), &despawned));
+        assert_eq!(
+            snapshots.get(&NetworkTick::new(10), &respawned),
+            Some(&Transform::from_xyz(1.0, 0.0, 0.0))
+        );
+    }
+
+    #[test]
+    pub fn check_updates_ahead() {
+        let mut world = World::new();
+        let entity = world.spawn((Predicted, Transform::default())).id();
+        let server_entity = ServerEntity::from_entity(Entity::from_raw(100));
+        let mut server_entities = ServerEntities::new();
+        server_entities.insert(server_entity, entity);
+        world.insert_resource(server_entities);
+        world.insert_resource(PredictionTolerance::<Transform>::Exact);
+        world.insert_resource(Mispredictions::new());
+
+        let mut snapshots = SnapshotBuffer::<Transform>::new();
+        for tick in 10..=12 {
+            snapshots.begin(NetworkTick::new(tick));
+            snapshots.update(entity, &Transform::default());
+        }
+        world.insert_resource(snapshots);
+
+        // We fell behind the server, which says we end up somewhere else on tick 12.
+        let mut components = ComponentsUpdate::new();
+        components.insert(
+            Transform::replicate_id(),
+            bincode::serialize(&Transform::from_xyz(1.0, 0.0, 0.0).into_def()).unwrap(),
+        );
+        let mut entity_update = EntityUpdate::new();
+        entity_update.insert(server_entity, components);
+        let mut server_updates = UpdateMessages::new();
+        server_updates.push(UpdateMessage {
+            tick: NetworkTick::new(12),
+            input_deviation: Default::default(),
+            input_margin: None,
+            entity_update,
+            events: Vec::new(),
+            remote_inputs: Default::default(),
+            component_despawn: Vec::new(),
+            entity_despawn: Vec::new(),
+        });
+        world.insert_resource(server_updates);
+
+        let mut check = SystemStage::single(client_check_prediction::<Transform>);
+        let mut clear = SystemStage::single(client_clear_checked_updates);
+
+        world.insert_resource(NetworkTick::new(10));
+        check.run(&mut world);
+        clear.run(&mut world);
+        assert!(world.get_resource::<Rewind>().is_none());
+
+        // A later frame, the update isn't in this frame's received ticks anymore.
+        world.resource_mut::<UpdateMessages>().clear_received();
+        world.insert_resource(NetworkTick::new(12));
+        check.run(&mut world);
+        clear.run(&mut world);
+        assert_eq!(
+            world.get_resource::<Rewind>().map(|rewind| rewind.0),
+            Some(NetworkTick::new(12))
+        );
+        assert_eq!(
+            world
+                .resource::<UpdateMessages>()
+                .unchecked(&NetworkTick::new(12))
+                .count(),
+            0
         );
     }
 }
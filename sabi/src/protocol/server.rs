@@ -1,5 +1,5 @@
 use bevy::prelude::*;
-use bevy_renet::renet::{RenetServer, ServerAuthentication, ServerConfig};
+use bevy_renet::renet::{RenetServer, ServerAuthentication, ServerConfig, ServerEvent};
 
 use std::{
     error::Error,
@@ -9,7 +9,13 @@ use std::{
 
 use std::time::SystemTime;
 
-use crate::protocol::*;
+use crate::protocol::{
+    event::ClientNetworkEvents,
+    input::{ClientMissingInputs, ClientRelayedInputs},
+    lag_compensation::ClientViewTicks,
+    validation::ClientInputViolations,
+    *,
+};
 
 pub fn new_renet_server<S: AsRef<str>>(
     local_ip: S,
@@ -93,3 +99,23 @@ pub fn new_renet_server<S: AsRef<str>>(
         socket,
     )?)
 }
+
+/// Forget everything we were keeping track of for clients that have left.
+pub fn server_clean_disconnected(
+    mut server_events: EventReader<ServerEvent>,
+    mut missing_inputs: ResMut<ClientMissingInputs>,
+    mut violations: ResMut<ClientInputViolations>,
+    mut relayed_inputs: ResMut<ClientRelayedInputs>,
+    mut network_events: ResMut<ClientNetworkEvents>,
+    mut view_ticks: ResMut<ClientViewTicks>,
+) {
+    for event in server_events.iter() {
+        if let ServerEvent::ClientDisconnected(client_id) = event {
+            missing_inputs.remove(client_id);
+            violations.remove(client_id);
+            relayed_inputs.remove(client_id);
+            network_events.remove(client_id);
+            view_ticks.remove(client_id);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use bevy::prelude::*;
+
+/// How prediction corrections are smoothed out visually.
+#[derive(Debug, Clone, Resource)]
+pub struct CorrectionSettings {
+    /// Roughly how many seconds it takes to blend out a correction.
+    pub decay_time: f32,
+    /// Corrections further off than this snap instead of blending, e.g. teleports.
+    pub max_error: f32,
+}
+
+impl Default for CorrectionSettings {
+    fn default() -> Self {
+        Self {
+            decay_time: 0.15,
+            max_error: 4.0,
+        }
+    }
+}
+
+/// Smooth out corrections to this entity's `Transform` after a rewind.
+///
+/// The simulated `Transform` is left exact, the leftover error is applied to any
+/// children with `CorrectionVisual`, so put meshes and the like on those.
+#[derive(Debug, Clone, Component)]
+pub struct SmoothCorrection {
+    pub translation_error: Vec3,
+    pub rotation_error: Quat,
+    /// Transform right before the last rewind.
+    pre_rewind: Option<Transform>,
+}
+
+impl Default for SmoothCorrection {
+    fn default() -> Self {
+        Self {
+            translation_error: Vec3::ZERO,
+            rotation_error: Quat::IDENTITY,
+            pre_rewind: None,
+        }
+    }
+}
+
+impl SmoothCorrection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember where we were before rewinding.
+    pub fn pre_rewind(&mut self, transform: Transform) {
+        if self.pre_rewind.is_none() {
+            self.pre_rewind = Some(transform);
+        }
+    }
+
+    /// Pick up the difference between where we were before rewinding and where we ended up,
+    /// so what is displayed doesn't move.
+    pub fn correct(&mut self, post_resim: &Transform, settings: &CorrectionSettings) {
+        let pre_rewind = match self.pre_rewind.take() {
+            Some(pre_rewind) => pre_rewind,
+            None => return,
+        };
+
+        let translation_error =
+            pre_rewind.translation + self.translation_error - post_resim.translation;
+        if translation_error.length() > settings.max_error {
+            self.translation_error = Vec3::ZERO;
+            self.rotation_error = Quat::IDENTITY;
+        } else {
+            self.translation_error = translation_error;
+            self.rotation_error =
+                self.rotation_error * pre_rewind.rotation * post_resim.rotation.inverse();
+        }
+    }
+
+    /// Blend the error out over `delta` seconds.
+    pub fn decay(&mut self, delta: f32, settings: &CorrectionSettings) {
+        let blend = if settings.decay_time > 0.0 {
+            (-delta / settings.decay_time).exp()
+        } else {
+            0.0
+        };
+
+        self.translation_error *= blend;
+        self.rotation_error = Quat::IDENTITY.slerp(self.rotation_error, blend);
+    }
+}
+
+/// Child of a `SmoothCorrection` or `Extrapolated` entity that displays the smoothed state.
+#[derive(Default, Debug, Clone, Copy, Component)]
+pub struct CorrectionVisual;
+
+pub fn client_record_pre_rewind(mut query: Query<(&Transform, &mut SmoothCorrection)>) {
+    for (transform, mut correction) in query.iter_mut() {
+        correction.pre_rewind(*transform);
+    }
+}
+
+pub fn client_smooth_corrections(
+    time: Res<Time>,
+    settings: Res<CorrectionSettings>,
+    mut query: Query<(&Transform, &mut SmoothCorrection, Option<&Children>)>,
+    mut visuals: Query<&mut Transform, (With<CorrectionVisual>, Without<SmoothCorrection>)>,
+) {
+    for (transform, mut correction, children) in query.iter_mut() {
+        correction.correct(transform, &*settings);
+        correction.decay(time.delta_seconds(), &*settings);
+
+        let children = match children {
+            Some(children) => children,
+            None => continue,
+        };
+
+        // Error is in world space, visuals are relative to us.
+        let inverse_rotation = transform.rotation.inverse();
+        for child in children.iter() {
+            if let Ok(mut visual) = visuals.get_mut(*child) {
+                visual.translation = inverse_rotation * correction.translation_error;
+                visual.rotation = inverse_rotation * correction.rotation_error * transform.rotation;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn smooth_correction() {
+        let settings = CorrectionSettings::default();
+        let mut correction = SmoothCorrection::new();
+
+        correction.pre_rewind(Transform::from_xyz(1.0, 0.0, 0.0));
+        correction.correct(&Transform::from_xyz(0.0, 0.0, 0.0), &settings);
+        assert_eq!(correction.translation_error, Vec3::X);
+
+        correction.decay(0.0, &settings);
+        assert_eq!(correction.translation_error, Vec3::X);
+        correction.decay(10.0, &settings);
+        assert!(correction.translation_error.length() < 1e-4);
+
+        // Teleported, don't try to smooth that out.
+        correction.pre_rewind(Transform::from_xyz(100.0, 0.0, 0.0));
+        correction.correct(&Transform::from_xyz(0.0, 0.0, 0.0), &settings);
+        assert_eq!(correction.translation_error, Vec3::ZERO);
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+
+use bevy::{ecs::entity::Entities, prelude::*};
+use bevy_renet::renet::RenetServer;
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+use super::resim::PredictedEntities;
+
+/// Ties an entity the client spawned ahead of the server to the one the server spawns.
+///
+/// Both sides have to come up with the same key, so build it from the player whose
+/// input spawned it, the tick the input was for and a sequence for spawns on that tick.
+/// Clients should spawn it along with `Predicted` so it gets rolled back.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Component,
+)]
+pub struct PredictionKey {
+    pub client: ClientId,
+    pub tick: NetworkTick,
+    pub sequence: u32,
+}
+
+impl PredictionKey {
+    pub fn new(client: ClientId, tick: NetworkTick, sequence: u32) -> Self {
+        Self {
+            client,
+            tick,
+            sequence,
+        }
+    }
+}
+
+/// How long predicted spawns wait for the server.
+#[derive(Debug, Clone, Resource)]
+pub struct PredictedSpawnSettings {
+    /// Ticks after the key's tick before we give up and despawn it.
+    pub timeout: u64,
+}
+
+impl Default for PredictedSpawnSettings {
+    fn default() -> Self {
+        Self { timeout: 64 }
+    }
+}
+
+/// Predicted spawns the server has confirmed, so replaying the tick
+/// they were spawned on doesn't spawn them again.
+#[derive(Default, Debug, Clone, Resource)]
+pub struct AdoptedSpawns {
+    adopted: BTreeMap<PredictionKey, Entity>,
+    /// Copies spawned from updates that beat the confirmation, and the predicted
+    /// entity their components are moved onto before they are despawned.
+    duplicates: Vec<(Entity, Entity)>,
+}
+
+impl AdoptedSpawns {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: PredictionKey, entity: Entity) {
+        self.adopted.insert(key, entity);
+    }
+
+    pub fn get(&self, key: &PredictionKey) -> Option<Entity> {
+        self.adopted.get(key).cloned()
+    }
+
+    pub fn insert_duplicate(&mut self, duplicate: Entity, predicted: Entity) {
+        self.duplicates.push((duplicate, predicted));
+    }
+
+    pub fn duplicates(&self) -> impl Iterator<Item = &(Entity, Entity)> {
+        self.duplicates.iter()
+    }
+
+    pub fn take_duplicates(&mut self) -> Vec<(Entity, Entity)> {
+        std::mem::take(&mut self.duplicates)
+    }
+
+    /// Forget keys too old to be replayed.
+    pub fn clean_old(&mut self, tick: NetworkTick, timeout: u64) {
+        self.adopted
+            .retain(|key, _| key.tick.tick() + timeout >= tick.tick());
+    }
+}
+
+/// Tell clients which of their predicted spawns we just spawned.
+pub fn server_confirm_predicted_spawns(
+    mut server: ResMut<RenetServer>,
+    query: Query<(Entity, &PredictionKey), Added<PredictionKey>>,
+) {
+    for (entity, key) in query.iter() {
+        ServerMessage::ConfirmSpawn {
+            entity: ServerEntity::from_entity(entity),
+            key: *key,
+        }
+        .send(&mut server, key.client);
+    }
+}
+
+/// Take over the server's entity with the one we predicted, if we still have it.
+pub fn adopt_predicted_spawn(
+    commands: &mut Commands,
+    entities: &Entities,
+    server_entities: &mut ServerEntities,
+    adopted: &mut AdoptedSpawns,
+    predicted: Option<Entity>,
+    server_entity: ServerEntity,
+    key: PredictionKey,
+) {
+    let predicted = match predicted {
+        Some(predicted) => predicted,
+        // Mispredicted or timed out, the server's entity spawns like any other.
+        None => return,
+    };
+
+    // Updates for it beat the confirmation here, so we already spawned a copy. It might
+    // hold the baseload, so it is only despawned once its components have been moved.
+    if let Some(duplicate) = server_entities.get(entities, server_entity) {
+        if duplicate != predicted {
+            adopted.insert_duplicate(duplicate, predicted);
+        }
+    }
+
+    commands.entity(predicted).insert(server_entity);
+    server_entities.insert(server_entity, predicted);
+    adopted.insert(key, predicted);
+}
+
+/// Move `C` from copies of adopted spawns onto the predicted entity that took over.
+pub fn client_move_duplicate_component<C>(
+    mut commands: Commands,
+    adopted: Res<AdoptedSpawns>,
+    query: Query<&C>,
+) where
+    C: 'static + Send + Sync + Component + Clone,
+{
+    for (duplicate, predicted) in adopted.duplicates() {
+        if let Ok(component) = query.get(*duplicate) {
+            commands.entity(*predicted).insert(component.clone());
+        }
+    }
+}
+
+/// Despawn copies of adopted spawns after their components have been moved.
+pub fn client_despawn_duplicate_spawns(mut commands: Commands, mut adopted: ResMut<AdoptedSpawns>) {
+    for (duplicate, _) in adopted.take_duplicates() {
+        commands.entity(duplicate).despawn_recursive();
+    }
+}
+
+/// Replaying the tick a confirmed spawn happened on spawns it again, keep the one we adopted.
+pub fn client_dedup_predicted_spawns(
+    mut commands: Commands,
+    adopted: Res<AdoptedSpawns>,
+    query: Query<(Entity, &PredictionKey), (Added<PredictionKey>, Without<ServerEntity>)>,
+) {
+    for (entity, key) in query.iter() {
+        if matches!(adopted.get(key), Some(adopted) if adopted != entity) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Despawn predicted spawns the server never confirmed.
+pub fn client_timeout_predicted_spawns(
+    mut commands: Commands,
+    tick: Res<NetworkTick>,
+    settings: Res<PredictedSpawnSettings>,
+    mut adopted: ResMut<AdoptedSpawns>,
+    mut predicted: ResMut<PredictedEntities>,
+    query: Query<(Entity, &PredictionKey), Without<ServerEntity>>,
+) {
+    for (entity, key) in query.iter() {
+        if key.tick.tick() + settings.timeout < tick.tick() {
+            commands.entity(entity).despawn_recursive();
+            // Otherwise rewinding past this would bring it back.
+            predicted.forget(&entity);
+        }
+    }
+
+    adopted.clean_old(*tick, settings.timeout);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn adopted_spawns() {
+        let key = PredictionKey::new(1, NetworkTick::new(10), 0);
+        let entity = Entity::from_raw(0);
+
+        let mut adopted = AdoptedSpawns::new();
+        adopted.insert(key, entity);
+        assert_eq!(adopted.get(&key), Some(entity));
+        assert_eq!(
+            adopted.get(&PredictionKey::new(1, NetworkTick::new(10), 1)),
+            None
+        );
+
+        adopted.clean_old(NetworkTick::new(74), 64);
+        assert_eq!(adopted.get(&key), Some(entity));
+        adopted.clean_old(NetworkTick::new(75), 64);
+        assert_eq!(adopted.get(&key), None);
+    }
+
+    #[derive(Component, Debug, Clone, PartialEq)]
+    struct Health(u32);
+
+    #[test]
+    pub fn adopt_and_dedup() {
+        let key = PredictionKey::new(1, NetworkTick::new(10), 0);
+        let server_entity = ServerEntity::from_entity(Entity::from_raw(100));
+
+        let mut world = World::new();
+        world.insert_resource(AdoptedSpawns::new());
+        let predicted = world.spawn((key, Health(10))).id();
+        // Updates beat the confirmation, so we spawned a copy with the baseload.
+        let duplicate = world.spawn((server_entity, Health(7))).id();
+        let mut server_entities = ServerEntities::new();
+        server_entities.insert(server_entity, duplicate);
+        world.insert_resource(server_entities);
+
+        let mut confirm = SystemStage::single(
+            move |mut commands: Commands,
+                  entities: &Entities,
+                  mut server_entities: ResMut<ServerEntities>,
+                  mut adopted: ResMut<AdoptedSpawns>| {
+                adopt_predicted_spawn(
+                    &mut commands,
+                    entities,
+                    &mut server_entities,
+                    &mut adopted,
+                    Some(predicted),
+                    server_entity,
+                    key,
+                );
+            },
+        );
+        let mut move_components = SystemStage::single(client_move_duplicate_component::<Health>);
+        let mut despawn = SystemStage::single(client_despawn_duplicate_spawns);
+        confirm.run(&mut world);
+        move_components.run(&mut world);
+        despawn.run(&mut world);
+
+        assert!(world.get_entity(duplicate).is_none());
+        assert_eq!(world.get::<Health>(predicted), Some(&Health(7)));
+        assert_eq!(world.get::<ServerEntity>(predicted), Some(&server_entity));
+        assert_eq!(
+            world
+                .resource::<ServerEntities>()
+                .get(world.entities(), server_entity),
+            Some(predicted)
+        );
+        assert_eq!(world.resource::<AdoptedSpawns>().get(&key), Some(predicted));
+
+        // Replaying the tick it was spawned on spawns it again.
+        let respawned = world.spawn(key).id();
+        let mut dedup = SystemStage::single(client_dedup_predicted_spawns);
+        dedup.run(&mut world);
+        assert!(world.get_entity(respawned).is_none());
+        assert!(world.get_entity(predicted).is_some());
+    }
+}
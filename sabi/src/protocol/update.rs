@@ -1,5 +1,5 @@
 use std::{
-    collections::{btree_map::Entry, BTreeMap},
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
     fmt::{self, Debug},
     time::Duration,
 };
@@ -9,14 +9,16 @@ use bevy_renet::renet::{RenetClient, RenetServer};
 
 use crate::{
     prelude::*,
-    stage::{NetworkSimulationInfo, Rewind},
+    stage::{NetworkSimulationInfo, RequestRewind},
 };
 use serde::{Deserialize, Serialize};
 
 use super::{
     demands::ReplicateSizeEstimates,
-    input::{ClientReceivedHistory, InputDeviation},
+    event::{ClientNetworkEvents, NetworkEventMessage, ReceivedNetworkEvents},
+    input::{ClientReceivedHistory, ClientRelayedInputs, InputDeviation, InputLead, InputMargin},
     interest::InterestsToSend,
+    interpolation::InterpolationBuffer,
     ClientId, NetworkTick,
 };
 
@@ -24,7 +26,13 @@ use super::{
 pub struct UpdateMessage {
     pub tick: NetworkTick,
     pub input_deviation: InputDeviation,
+    /// How early our inputs have been arriving at the server, if it has gotten any.
+    pub input_margin: Option<InputMargin>,
     pub entity_update: EntityUpdate,
+    /// Unreliable events, repeated in each update until the client acks them.
+    pub events: Vec<NetworkEventMessage>,
+    /// Latest inputs of other players near us, if the server is relaying them.
+    pub remote_inputs: BTreeMap<ServerEntity, Vec<u8>>,
 
     // Clean up stragglers.
     pub component_despawn: Vec<(ServerEntity, ReplicateId)>,
@@ -38,6 +46,8 @@ impl UpdateMessage {
         }
 
         self.entity_update.apply(other.entity_update);
+        self.events.extend(other.events);
+        self.remote_inputs.extend(other.remote_inputs);
     }
 }
 
@@ -152,19 +162,26 @@ impl Debug for ComponentsUpdate {
 
 impl EntityUpdate {
     pub fn protocol_id() -> u64 {
-        1
+        4
     }
 }
 
 #[derive(Debug, Clone, Resource)]
 pub struct UpdateMessages {
     messages: BTreeMap<NetworkTick, UpdateMessage>,
+    /// Ticks we got updates for this frame.
+    received: BTreeSet<NetworkTick>,
+    /// Ticks we got updates for that haven't been checked against our predictions,
+    /// since we can't check a tick we haven't simulated yet.
+    unchecked: BTreeSet<NetworkTick>,
 }
 
 impl UpdateMessages {
     pub fn new() -> Self {
         Self {
             messages: Default::default(),
+            received: Default::default(),
+            unchecked: Default::default(),
         }
     }
 
@@ -172,11 +189,65 @@ impl UpdateMessages {
         self.messages.get(tick)
     }
 
+    /// Ticks we got updates for this frame, oldest first.
+    pub fn received(&self) -> impl Iterator<Item = &NetworkTick> {
+        self.received.iter()
+    }
+
+    pub fn clear_received(&mut self) {
+        self.received.clear();
+    }
+
+    /// Ticks up to `tick` that haven't been checked against our predictions, oldest first.
+    pub fn unchecked(&self, tick: &NetworkTick) -> impl Iterator<Item = &NetworkTick> {
+        self.unchecked.range(..=*tick)
+    }
+
+    /// Mark ticks up to `tick` as checked.
+    pub fn clear_checked(&mut self, tick: &NetworkTick) {
+        self.unchecked = self.unchecked.split_off(&NetworkTick::new(tick.tick() + 1));
+    }
+
+    /// Most recent input relayed for a remote player before `tick`.
+    pub fn last_remote_input(
+        &self,
+        tick: &NetworkTick,
+        server_entity: &ServerEntity,
+    ) -> Option<&Vec<u8>> {
+        self.messages
+            .range(..*tick)
+            .rev()
+            .find_map(|(_, message)| message.remote_inputs.get(server_entity))
+    }
+
+    /// Latest input relayed for each remote player on or before `tick`.
+    pub fn remote_inputs(&self, tick: &NetworkTick) -> BTreeMap<&ServerEntity, &Vec<u8>> {
+        let mut inputs = BTreeMap::new();
+        for (_, message) in self.messages.range(..=*tick) {
+            inputs.extend(message.remote_inputs.iter());
+        }
+        inputs
+    }
+
+    /// Most recent tick we got `replicate_id` for `server_entity`.
+    pub fn last_component_update(
+        &self,
+        server_entity: &ServerEntity,
+        replicate_id: &ReplicateId,
+    ) -> Option<&NetworkTick> {
+        self.messages.iter().rev().find_map(|(tick, message)| {
+            let components = message.entity_update.get(server_entity)?;
+            components.get(replicate_id).map(|_| tick)
+        })
+    }
+
     pub fn latest(&self) -> Option<&NetworkTick> {
         self.messages.keys().max()
     }
 
     pub fn push(&mut self, message: UpdateMessage) {
+        self.received.insert(message.tick);
+        self.unchecked.insert(message.tick);
         match self.messages.entry(message.tick) {
             Entry::Occupied(mut entry) => {
                 entry.get_mut().apply(message);
@@ -215,11 +286,15 @@ pub fn client_recv_interest(
     tick: Option<Res<NetworkTick>>,
     mut commands: Commands,
     mut network_sim_info: ResMut<NetworkSimulationInfo>,
+    input_lead: Res<InputLead>,
     mut server_updates: ResMut<UpdateMessages>,
     mut server_entities: ResMut<ServerEntities>,
+    mut network_events: ResMut<ReceivedNetworkEvents>,
     mut client: ResMut<RenetClient>,
 ) {
+    // Only rewind for relayed inputs, mispredicted components are checked per type.
     let mut rewind: Option<NetworkTick> = None;
+    server_updates.clear_received();
 
     while let Some(message) = client.receive_message(ServerChannel::EntityUpdate.id()) {
         /*
@@ -235,21 +310,26 @@ pub fn client_recv_interest(
             .decompress(&message.as_slice(), 10 * 1024)
             .expect("could not decompress message");
 
-        let message: UpdateMessage = bincode::deserialize(&decompressed).unwrap();
+        let mut message: UpdateMessage = bincode::deserialize(&decompressed).unwrap();
 
         let frame_buffer =
             client_frame_buffer(&*network_sim_info, &client, &message.input_deviation);
 
         match tick {
-            Some(ref tick) => {
-                let diff = (tick.tick() as i64 - message.tick.tick() as i64) as f32
-                    * network_sim_info.step.as_secs_f32();
-                if diff > frame_buffer {
-                    network_sim_info.decel(0.01);
-                } else if diff < frame_buffer {
-                    network_sim_info.accel(0.01);
+            Some(ref tick) => match message.input_margin {
+                // Steer by how early the server says our inputs are arriving.
+                Some(margin) => input_lead.steer(&mut *network_sim_info, margin),
+                // Server hasn't gotten any inputs yet, so estimate from the tick difference.
+                None => {
+                    let diff = (tick.tick() as i64 - message.tick.tick() as i64) as f32
+                        * network_sim_info.step.as_secs_f32();
+                    if diff > frame_buffer {
+                        network_sim_info.decel(0.01);
+                    } else if diff < frame_buffer {
+                        network_sim_info.accel(0.01);
+                    }
                 }
-            }
+            },
             None => {
                 dbg!("first tick", &message.tick);
                 commands.insert_resource(message.tick);
@@ -258,28 +338,46 @@ pub fn client_recv_interest(
             }
         }
 
-        match rewind {
-            Some(ref mut rewind) if message.tick.tick() < rewind.tick() => {
-                *rewind = message.tick;
-            }
-            None => {
-                rewind = Some(message.tick);
+        let inputs_changed = message.remote_inputs.iter().any(|(server_entity, input)| {
+            server_updates.last_remote_input(&message.tick, server_entity) != Some(input)
+        });
+        if inputs_changed {
+            match rewind {
+                Some(ref mut rewind) if message.tick.tick() < rewind.tick() => {
+                    *rewind = message.tick;
+                }
+                None => {
+                    rewind = Some(message.tick);
+                }
+                _ => {}
             }
-            _ => {}
         }
 
         for (server_entity, _) in message.entity_update.iter() {
             server_entities.spawn_or_get(&mut commands, *server_entity);
         }
 
+        // Events are repeated across updates, only keep the first copy so they fire once.
+        for event in message.events.drain(..) {
+            network_events.receive(event);
+        }
+
         server_updates.push(message);
     }
 
     if let Some(rewind) = rewind {
-        commands.insert_resource(Rewind(rewind));
+        commands.add(RequestRewind(rewind));
     }
 }
 
+/// Forget updates every component has been checked against, runs after the checks.
+pub fn client_clear_checked_updates(
+    tick: Res<NetworkTick>,
+    mut server_updates: ResMut<UpdateMessages>,
+) {
+    server_updates.clear_checked(&*tick);
+}
+
 pub fn client_apply_server_update(
     tick: Res<NetworkTick>,
     server_updates: Res<UpdateMessages>,
@@ -295,9 +393,11 @@ pub fn client_apply_server_update(
 pub fn client_update<C>(
     mut commands: Commands,
     entities: &Entities,
+    tick: Res<NetworkTick>,
     server_entities: Res<ServerEntities>,
     mut update_events: EventReader<(ServerEntity, ComponentsUpdate)>,
     mut query: Query<&mut C>,
+    mut interpolated: Query<&mut InterpolationBuffer<C>>,
 ) where
     C: 'static + Send + Sync + Component + Replicate + Clone,
 {
@@ -305,7 +405,10 @@ pub fn client_update<C>(
         if let Some(update_data) = components_update.get(&C::replicate_id()) {
             let def: <C as Replicate>::Def = bincode::deserialize(&update_data).unwrap();
             if let Some(entity) = server_entities.get(entities, *server_entity) {
-                if let Ok(mut component) = query.get_mut(entity) {
+                if let Ok(mut buffer) = interpolated.get_mut(entity) {
+                    // Rendered from the buffer instead of being applied directly.
+                    buffer.push(*tick, C::from_def(def));
+                } else if let Ok(mut component) = query.get_mut(entity) {
                     let current_def = component.clone().into_def();
                     if current_def != def {
                         //info!("updating component");
@@ -369,6 +472,8 @@ pub fn server_queue_interest<C>(
 pub fn server_send_interest(
     tick: Res<NetworkTick>,
     mut history: ResMut<ClientReceivedHistory>,
+    mut network_events: ResMut<ClientNetworkEvents>,
+    mut relayed_inputs: ResMut<ClientRelayedInputs>,
     updates: Res<ClientEntityUpdates>,
     mut server: ResMut<RenetServer>,
 ) {
@@ -381,16 +486,22 @@ pub fn server_send_interest(
     */
     let mut compressor = zstd::bulk::Compressor::new(0).expect("couldn't make compressor");
 
+    network_events.clean_old(*tick);
+
     for (client_id, update) in updates.iter() {
         if !server.can_send_message(*client_id, ServerChannel::EntityUpdate.id()) {
             continue;
         }
 
-        if update.iter().count() == 0 {
+        if update.iter().count() == 0
+            && !network_events.has_pending(client_id)
+            && !relayed_inputs.has_pending(client_id)
+        {
             continue;
         }
 
         let input_deviation = history.deviation(*client_id);
+        let input_margin = history.margin(client_id);
 
         //info!("update: {:?}", &update);
 
@@ -398,7 +509,10 @@ pub fn server_send_interest(
         let message = UpdateMessage {
             tick: *tick,
             input_deviation: input_deviation,
+            input_margin,
             entity_update: update.clone(),
+            events: network_events.to_send(client_id),
+            remote_inputs: relayed_inputs.take(client_id),
 
             component_despawn: Vec::new(),
             entity_despawn: Vec::new(),
@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+
+use bevy::prelude::*;
+
+use super::{
+    input::{QueuedInputs, INPUT_SEND_BUFFER},
+    ClientId, NetworkTick,
+};
+
+/// How far ahead of the server a client can send inputs for by default.
+pub const MAX_FUTURE_INPUT_TICKS: u64 = 64;
+
+/// What a validator decided to do with an input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputVerdict {
+    /// Input is fine as is.
+    Accept,
+    /// Input was out of range and has been modified to fit.
+    Clamped,
+    /// Input should be thrown away.
+    Reject,
+}
+
+/// Why an input from a client was modified or thrown away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum InputViolation {
+    /// Message couldn't be decompressed or deserialized.
+    Malformed,
+    /// More inputs in a single message than we allow.
+    TooManyInputs,
+    /// Input was for a tick too far ahead of the server.
+    FutureTick,
+    /// Validator modified the input.
+    Clamped,
+    /// Validator rejected the input.
+    Rejected,
+}
+
+/// Sent whenever a client sends us something we didn't like, mainly for anti-cheat.
+#[derive(Debug, Clone)]
+pub struct InputViolationEvent {
+    pub client_id: ClientId,
+    pub tick: Option<NetworkTick>,
+    pub violation: InputViolation,
+}
+
+/// Checks inputs from clients before they get queued up.
+///
+/// Inputs can be clamped in place or rejected altogether.
+pub trait InputValidator<I>: 'static + Send + Sync {
+    fn validate(&self, client_id: ClientId, tick: NetworkTick, input: &mut I) -> InputVerdict;
+}
+
+impl<I, F> InputValidator<I> for F
+where
+    F: Fn(ClientId, NetworkTick, &mut I) -> InputVerdict + 'static + Send + Sync,
+{
+    fn validate(&self, client_id: ClientId, tick: NetworkTick, input: &mut I) -> InputVerdict {
+        self(client_id, tick, input)
+    }
+}
+
+/// Accepts every input.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AcceptAll;
+
+impl<I> InputValidator<I> for AcceptAll {
+    fn validate(&self, _client_id: ClientId, _tick: NetworkTick, _input: &mut I) -> InputVerdict {
+        InputVerdict::Accept
+    }
+}
+
+/// Rules for sanitizing inputs from clients, accepts everything by default.
+#[derive(Resource)]
+pub struct InputValidation<I> {
+    pub validator: Box<dyn InputValidator<I>>,
+    /// Maximum ticks ahead of the server an input can be for.
+    pub max_future_ticks: u64,
+    /// Maximum inputs per player in a single message.
+    pub max_inputs: usize,
+}
+
+impl<I> Default for InputValidation<I> {
+    fn default() -> Self {
+        Self::new(AcceptAll)
+    }
+}
+
+impl<I> InputValidation<I> {
+    pub fn new(validator: impl InputValidator<I>) -> Self {
+        Self {
+            validator: Box::new(validator),
+            max_future_ticks: MAX_FUTURE_INPUT_TICKS,
+            max_inputs: INPUT_SEND_BUFFER as usize,
+        }
+    }
+
+    /// Remove or clamp any inputs that break the rules, returning what was broken.
+    pub fn sanitize(
+        &self,
+        client_id: ClientId,
+        current: NetworkTick,
+        inputs: &mut QueuedInputs<I>,
+    ) -> Vec<(Option<NetworkTick>, InputViolation)> {
+        let mut violations = Vec::new();
+
+        if inputs.len() > self.max_inputs {
+            violations.push((None, InputViolation::TooManyInputs));
+            inputs.truncate_oldest(self.max_inputs);
+        }
+
+        let max_tick = current.tick().saturating_add(self.max_future_ticks);
+        inputs.retain_inputs(|tick, input| {
+            if tick.tick() > max_tick {
+                violations.push((Some(*tick), InputViolation::FutureTick));
+                return false;
+            }
+
+            match self.validator.validate(client_id, *tick, input) {
+                InputVerdict::Accept => true,
+                InputVerdict::Clamped => {
+                    violations.push((Some(*tick), InputViolation::Clamped));
+                    true
+                }
+                InputVerdict::Reject => {
+                    violations.push((Some(*tick), InputViolation::Rejected));
+                    false
+                }
+            }
+        });
+
+        violations
+    }
+}
+
+/// How many times each client has broken the input rules.
+#[derive(Default, Debug, Clone, Resource)]
+pub struct ClientInputViolations {
+    clients: BTreeMap<ClientId, BTreeMap<InputViolation, u64>>,
+}
+
+impl ClientInputViolations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, client_id: ClientId, violation: InputViolation) {
+        *self
+            .clients
+            .entry(client_id)
+            .or_default()
+            .entry(violation)
+            .or_default() += 1;
+    }
+
+    pub fn get(&self, client_id: &ClientId) -> Option<&BTreeMap<InputViolation, u64>> {
+        self.clients.get(client_id)
+    }
+
+    /// Total number of violations for a client.
+    pub fn total(&self, client_id: &ClientId) -> u64 {
+        self.clients
+            .get(client_id)
+            .map(|violations| violations.values().sum())
+            .unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ClientId, &BTreeMap<InputViolation, u64>)> {
+        self.clients.iter()
+    }
+
+    pub fn remove(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn sanitize() {
+        let validation = InputValidation::<u32>::new(
+            |_client_id: ClientId, _tick: NetworkTick, input: &mut u32| {
+                if *input == 0 {
+                    InputVerdict::Reject
+                } else if *input > 10 {
+                    *input = 10;
+                    InputVerdict::Clamped
+                } else {
+                    InputVerdict::Accept
+                }
+            },
+        );
+
+        let current = NetworkTick::new(100);
+        let mut inputs = QueuedInputs::new();
+        inputs.upsert(NetworkTick::new(100), 5);
+        inputs.upsert(NetworkTick::new(101), 0);
+        inputs.upsert(NetworkTick::new(102), 50);
+        inputs.upsert(NetworkTick::new(100 + MAX_FUTURE_INPUT_TICKS + 1), 5);
+
+        let violations = validation.sanitize(1, current, &mut inputs);
+        let violations = violations
+            .into_iter()
+            .map(|(_, violation)| violation)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            violations,
+            vec![
+                InputViolation::Rejected,
+                InputViolation::Clamped,
+                InputViolation::FutureTick
+            ]
+        );
+
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(inputs.get(&NetworkTick::new(102)), Some(&10));
+    }
+
+    #[test]
+    pub fn too_many_inputs() {
+        let validation = InputValidation::<u32>::default();
+
+        let mut inputs = QueuedInputs::new();
+        for tick in 0..(INPUT_SEND_BUFFER as u64 * 2) {
+            inputs.upsert(NetworkTick::new(tick), 1);
+        }
+
+        let violations = validation.sanitize(1, NetworkTick::new(0), &mut inputs);
+        assert_eq!(violations, vec![(None, InputViolation::TooManyInputs)]);
+        assert_eq!(inputs.len(), INPUT_SEND_BUFFER as usize);
+        assert!(inputs.get(&NetworkTick::new(0)).is_none());
+    }
+}
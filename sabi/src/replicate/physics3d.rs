@@ -3,12 +3,24 @@ use bevy_rapier3d::{prelude::*, rapier::prelude::SharedShape};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{plugin::ReplicatePlugin, protocol::demands::RequireDependency, Replicate};
+use crate::{
+    plugin::{init_prediction_tolerance, ReplicatePlugin},
+    protocol::{demands::RequireDependency, resim::PredictionTolerance},
+    Replicate,
+};
 
 pub struct ReplicatePhysics3dPlugin;
 impl Plugin for ReplicatePhysics3dPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugin(ReplicatePlugin::<RigidBody>::default());
+        // Solver output, so it is never bit-identical to the server's.
+        init_prediction_tolerance(
+            app,
+            PredictionTolerance::<Velocity>::Within(|predicted, server| {
+                predicted.linvel.distance(server.linvel) < 0.01
+                    && predicted.angvel.distance(server.angvel) < 0.01
+            }),
+        );
         app.add_plugin(ReplicatePlugin::<Velocity>::default());
         app.add_plugin(ReplicatePlugin::<LockedAxes>::default());
         app.add_plugin(ReplicatePlugin::<ExternalForce>::default());
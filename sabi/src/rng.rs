@@ -0,0 +1,124 @@
+use bevy::prelude::*;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::tick::NetworkTick;
+
+/// Deterministic random numbers for the simulation.
+///
+/// The server picks the seed and sends it to clients, and the shared stream is
+/// reseeded from the `NetworkTick` at the start of every tick, so predicted and
+/// resimulated ticks roll the same numbers the server did. It is also snapshotted
+/// and restored on rewinds, so draws made outside the tick's reseed replay the same.
+///
+/// ChaCha8 is used because `StdRng` can change algorithm between `rand` versions
+/// and platforms, and every peer has to get the same numbers.
+///
+/// Systems don't run in a fixed order, so two systems drawing from the shared stream
+/// on the same tick can get each other's numbers. Use `stream` or `entity` to get a
+/// source of their own.
+#[derive(Debug, Clone, Resource)]
+pub struct NetworkRng {
+    seed: u64,
+    rng: ChaCha8Rng,
+}
+
+impl Default for NetworkRng {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl NetworkRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Reseed the shared stream for `tick`.
+    pub fn set_tick(&mut self, tick: NetworkTick) {
+        self.rng = self.stream(tick, 0);
+    }
+
+    /// Random source for `stream` on `tick`, the same every time it's asked for.
+    pub fn stream(&self, tick: NetworkTick, stream: u64) -> ChaCha8Rng {
+        ChaCha8Rng::seed_from_u64(mix(mix(self.seed, tick.tick()), stream))
+    }
+
+    /// Random source for an entity on `tick`.
+    #[cfg(feature = "public")]
+    pub fn entity(&self, tick: NetworkTick, entity: crate::protocol::ServerEntity) -> ChaCha8Rng {
+        self.stream(tick, entity.to_bits())
+    }
+}
+
+impl RngCore for NetworkRng {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+/// SplitMix64 of two values, so nearby ticks and streams end up with unrelated seeds.
+fn mix(a: u64, b: u64) -> u64 {
+    let mut z = a.wrapping_add(b.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+pub fn reseed_network_rng(tick: Res<NetworkTick>, mut rng: ResMut<NetworkRng>) {
+    rng.set_tick(*tick);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rand::Rng;
+
+    #[test]
+    pub fn deterministic() {
+        let tick = NetworkTick::new(10);
+        let server = NetworkRng::new(1234);
+        let client = NetworkRng::new(1234);
+
+        assert_eq!(
+            server.stream(tick, 1).gen::<u64>(),
+            client.stream(tick, 1).gen::<u64>()
+        );
+        assert_ne!(
+            server.stream(tick, 1).gen::<u64>(),
+            server.stream(tick, 2).gen::<u64>()
+        );
+        assert_ne!(
+            server.stream(tick, 1).gen::<u64>(),
+            server.stream(NetworkTick::new(11), 1).gen::<u64>()
+        );
+
+        // Resimulating a tick rolls the same numbers again.
+        let mut rng = NetworkRng::new(1234);
+        rng.set_tick(tick);
+        let first = (rng.gen::<u32>(), rng.gen::<f32>());
+        rng.gen::<u64>();
+        rng.set_tick(tick);
+        assert_eq!(first, (rng.gen::<u32>(), rng.gen::<f32>()));
+    }
+}
@@ -1,7 +1,9 @@
 use std::time::Duration;
 
+use bevy::ecs::event::Event;
 use bevy::ecs::prelude::*;
 use bevy::ecs::schedule::IntoSystemDescriptor;
+use bevy::ecs::system::{Command, SystemParam};
 use bevy::prelude::*;
 
 use crate::tick::NetworkTick;
@@ -131,9 +133,66 @@ impl NetworkSimulationStage {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Resource)]
 pub struct Rewind(pub NetworkTick);
 
+/// Command to rewind to a tick, keeping whichever rewind is earliest if one is already queued.
+#[derive(Debug, Clone)]
+pub struct RequestRewind(pub NetworkTick);
+
+impl Command for RequestRewind {
+    fn write(self, world: &mut World) {
+        match world.get_resource_mut::<Rewind>() {
+            Some(mut rewind) => {
+                if self.0 < rewind.0 {
+                    rewind.0 = self.0;
+                }
+            }
+            None => world.insert_resource(Rewind(self.0)),
+        }
+    }
+}
+
+/// Whether the simulation is currently replaying ticks after a rewind.
+///
+/// Anything with side effects like sounds, particles or events should only happen
+/// on the first simulation of a tick, otherwise it will happen again on every correction.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Resource)]
+pub struct IsResimulating(pub bool);
+
+/// Run condition for systems that should only run while replaying ticks.
+pub fn is_resimulating(resimulating: Option<Res<IsResimulating>>) -> bool {
+    resimulating
+        .map(|resimulating| resimulating.0)
+        .unwrap_or(false)
+}
+
+/// Run condition for systems that should only run the first time a tick is simulated.
+pub fn is_first_simulation(resimulating: Option<Res<IsResimulating>>) -> bool {
+    !is_resimulating(resimulating)
+}
+
+/// `EventWriter` that drops events sent while resimulating.
+#[derive(SystemParam)]
+pub struct FirstSimulationEvents<'w, 's, E: Event> {
+    resimulating: Res<'w, IsResimulating>,
+    events: EventWriter<'w, 's, E>,
+}
+
+impl<'w, 's, E: Event> FirstSimulationEvents<'w, 's, E> {
+    pub fn send(&mut self, event: E) {
+        if !self.resimulating.0 {
+            self.events.send(event);
+        }
+    }
+
+    pub fn send_batch(&mut self, events: impl IntoIterator<Item = E>) {
+        if !self.resimulating.0 {
+            self.events.send_batch(events);
+        }
+    }
+}
+
 impl Stage for NetworkSimulationStage {
     fn run(&mut self, world: &mut World) {
         if let Some(info) = world.get_resource::<NetworkSimulationInfo>() {
@@ -159,6 +218,7 @@ impl Stage for NetworkSimulationStage {
         };
 
         world.insert_resource(self.info.clone());
+        world.insert_resource(IsResimulating(false));
 
         let increment_network_tick = |world: &mut World| {
             world
@@ -196,6 +256,7 @@ impl Stage for NetworkSimulationStage {
 
                 if rewind_tick.tick() <= current_tick.tick() {
                     world.insert_resource(bevy::ecs::schedule::ReportExecutionOrderAmbiguities);
+                    world.insert_resource(IsResimulating(true));
 
                     world.insert_resource(rewind_tick);
                     /*
@@ -227,6 +288,7 @@ impl Stage for NetworkSimulationStage {
                     }
 
                     world.remove_resource::<bevy::ecs::schedule::ReportExecutionOrderAmbiguities>();
+                    world.insert_resource(IsResimulating(false));
                 }
 
                 let resimmed_current_tick = world